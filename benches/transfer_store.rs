@@ -0,0 +1,123 @@
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Instant;
+
+use rust_decimal::Decimal;
+use tiny_transaction_processor::*;
+
+// Counts the bytes currently allocated, so the memory taken by a store is the difference before
+// and after filling it
+struct CountingAllocator;
+
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATED.fetch_add(layout.size(), Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        ALLOCATED.fetch_sub(layout.size(), Ordering::Relaxed);
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATED.fetch_add(new_size, Ordering::Relaxed);
+        ALLOCATED.fetch_sub(layout.size(), Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+const TRANSFERS: u32 = 2_000_000;
+const SPARSE_TRANSFERS: u32 = 20_000;
+// One transfer for every page of the compact store
+const SPARSE_SPACING: u32 = 4096;
+
+fn transfer(id: u32) -> Transfer {
+    Transfer {
+        transfer_type: if id.is_multiple_of(4) {
+            TransferType::Withdrawal
+        } else {
+            TransferType::Deposit
+        },
+        client_id: ClientID::new((id % 1000) as u16),
+        transaction_id: TransactionID::new(id),
+        amount: Decimal::new((id % 100_000) as i64, 4),
+        timestamp: None,
+    }
+}
+
+// The transfers get the IDs `0, spacing, 2 * spacing, ...`
+fn measure(name: &str, mut store: Box<dyn TransferStore>, transfers: u32, spacing: u32) {
+    let allocated_before = ALLOCATED.load(Ordering::Relaxed);
+
+    let started = Instant::now();
+    for index in 0..transfers {
+        store.insert_transfer(transfer(index * spacing));
+    }
+    for index in (0..transfers).step_by(100) {
+        let id = index * spacing;
+        store.set_disputed_amount(TransactionID::new(id), Some(transfer(id).amount));
+    }
+    let inserted = started.elapsed();
+
+    let started = Instant::now();
+    let mut found = 0;
+    for index in (0..transfers).map(|index| index.wrapping_mul(2_654_435_761) % transfers) {
+        if store
+            .transfer(TransactionID::new(index * spacing))
+            .is_some()
+        {
+            found += 1;
+        }
+    }
+    let looked_up = started.elapsed();
+    assert_eq!(found, transfers);
+
+    let allocated = ALLOCATED.load(Ordering::Relaxed) - allocated_before;
+    println!(
+        "{:<8} {:>8.1} bytes per transfer, {:>6} ms to insert, {:>6} ms to look up",
+        name,
+        allocated as f64 / transfers as f64,
+        inserted.as_millis(),
+        looked_up.as_millis()
+    );
+}
+
+fn main() {
+    println!("{} transfers, 1% of them disputed", TRANSFERS);
+    measure(
+        "memory",
+        Box::new(MemoryTransferStore::default()),
+        TRANSFERS,
+        1,
+    );
+    measure(
+        "compact",
+        Box::new(CompactTransferStore::default()),
+        TRANSFERS,
+        1,
+    );
+
+    println!();
+    println!(
+        "{} transfers with IDs {} apart, 1% of them disputed",
+        SPARSE_TRANSFERS, SPARSE_SPACING
+    );
+    measure(
+        "memory",
+        Box::new(MemoryTransferStore::default()),
+        SPARSE_TRANSFERS,
+        SPARSE_SPACING,
+    );
+    measure(
+        "compact",
+        Box::new(CompactTransferStore::default()),
+        SPARSE_TRANSFERS,
+        SPARSE_SPACING,
+    );
+}
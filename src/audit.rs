@@ -0,0 +1,185 @@
+use std::collections::HashMap;
+
+use rust_decimal::{prelude::Zero, Decimal};
+
+use crate::{
+    ClientID, LedgerAccount, TransactionID, TransactionProcessor, TransferType,
+    WithdrawalDisputePolicy,
+};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Discrepancy {
+    // Held funds have to be the sum of the open disputes of the client
+    HeldMismatch {
+        client_id: ClientID,
+        held: Decimal,
+        disputed: Decimal,
+    },
+    // Total has to follow from the stored transfers and their disputes and chargebacks
+    TotalMismatch {
+        client_id: ClientID,
+        total: Decimal,
+        expected: Decimal,
+    },
+    // A dispute or a chargeback of a transfer that isn't stored
+    UnknownTransfer(TransactionID),
+    // Disputed and charged back parts of a transfer together can't exceed it
+    TransferOverdisputed {
+        transaction_id: TransactionID,
+        amount: Decimal,
+        disputed: Decimal,
+        charged_back: Decimal,
+    },
+    NegativeHeld(ClientID),
+    MissingAccount(ClientID),
+    // Client accounts have to match their balances in the ledger
+    LedgerMismatch {
+        client_id: ClientID,
+        available: Decimal,
+        held: Decimal,
+    },
+    // Debits and credits of all the ledger accounts have to be equal
+    UnbalancedLedger {
+        debit: Decimal,
+        credit: Decimal,
+    },
+    // The house account has to hold all the fees charged to the clients
+    FeesMismatch {
+        charged: Decimal,
+        house: Decimal,
+    },
+}
+
+impl std::fmt::Display for Discrepancy {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+#[derive(Default)]
+struct ExpectedBalances {
+    held: Decimal,
+    total: Decimal,
+}
+
+impl TransactionProcessor {
+    // Recomputes the accounts from the stored transfers and their dispute state. Totals can only be
+    // recomputed while all the transfers are stored, so they aren't checked once some have expired.
+    // The withdrawal dispute policy has to be the one the transactions were processed with, which is
+    // ensured for loaded states by `try_with_config`.
+    pub fn verify(&self) -> Vec<Discrepancy> {
+        let mut discrepancies = Vec::new();
+        let credit_held = self.config.withdrawal_disputes == WithdrawalDisputePolicy::CreditHeld;
+
+        let mut expected = HashMap::<ClientID, ExpectedBalances>::new();
+        for transfer in self.store.transfers() {
+            let transaction_id = transfer.transaction_id;
+            let disputed = self
+                .store
+                .disputed_amount(transaction_id)
+                .unwrap_or_default();
+            let charged_back = self
+                .store
+                .charged_back_amount(transaction_id)
+                .unwrap_or_default();
+            if disputed + charged_back > transfer.amount {
+                discrepancies.push(Discrepancy::TransferOverdisputed {
+                    transaction_id,
+                    amount: transfer.amount,
+                    disputed,
+                    charged_back,
+                });
+            }
+
+            let balances = expected.entry(transfer.client_id).or_default();
+            balances.held += disputed;
+            balances.total += match transfer.transfer_type {
+                TransferType::Deposit => transfer.amount - charged_back,
+                // Disputes of withdrawals credit the held funds back instead of holding available
+                TransferType::Withdrawal if credit_held => {
+                    -transfer.amount + disputed + charged_back
+                }
+                TransferType::Withdrawal => -transfer.amount - charged_back,
+            };
+        }
+        // Client transfers can't be disputed, so only their amounts count
+        for client_transfer in self.client_transfers.values() {
+            expected.entry(client_transfer.client_id).or_default().total -= client_transfer.amount;
+            expected
+                .entry(client_transfer.destination_client_id)
+                .or_default()
+                .total += client_transfer.amount;
+        }
+        for (client_id, fees) in &self.fees {
+            expected.entry(*client_id).or_default().total -= *fees;
+        }
+        for (transaction_id, _) in self
+            .store
+            .disputed_amounts()
+            .chain(self.store.charged_back_amounts())
+        {
+            if self.store.transfer(transaction_id).is_none() {
+                discrepancies.push(Discrepancy::UnknownTransfer(transaction_id));
+            }
+        }
+
+        let mut client_ids = self
+            .accounts
+            .keys()
+            .chain(expected.keys())
+            .copied()
+            .collect::<Vec<_>>();
+        client_ids.sort_unstable();
+        client_ids.dedup();
+        for client_id in client_ids {
+            let account = match self.accounts.get(&client_id) {
+                Some(account) => account,
+                None => {
+                    discrepancies.push(Discrepancy::MissingAccount(client_id));
+                    continue;
+                }
+            };
+            let balances = expected.remove(&client_id).unwrap_or_default();
+            let (available, held) = self.ledger.client_balances(client_id);
+            if (available, held) != (account.available, account.held) {
+                discrepancies.push(Discrepancy::LedgerMismatch {
+                    client_id,
+                    available,
+                    held,
+                });
+            }
+            if account.held < Decimal::zero() {
+                discrepancies.push(Discrepancy::NegativeHeld(client_id));
+            }
+            if account.held != balances.held {
+                discrepancies.push(Discrepancy::HeldMismatch {
+                    client_id,
+                    held: account.held,
+                    disputed: balances.held,
+                });
+            }
+            if self.expired.is_empty() && account.total() != balances.total {
+                discrepancies.push(Discrepancy::TotalMismatch {
+                    client_id,
+                    total: account.total(),
+                    expected: balances.total,
+                });
+            }
+        }
+
+        let charged = self.fees.values().sum::<Decimal>();
+        let house = self.ledger.balance(LedgerAccount::House).credit_balance();
+        if house != charged {
+            discrepancies.push(Discrepancy::FeesMismatch { charged, house });
+        }
+        if let Some(total) = self.ledger.trial_balance().pop() {
+            if total.debit != total.credit {
+                discrepancies.push(Discrepancy::UnbalancedLedger {
+                    debit: total.debit,
+                    credit: total.credit,
+                });
+            }
+        }
+        discrepancies
+    }
+}
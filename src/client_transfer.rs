@@ -0,0 +1,139 @@
+use crate::{
+    Change, ClientTransfer, LedgerAccount, Posting, ProcessingError, Transaction,
+    TransactionProcessor,
+};
+
+// The processor keeping the destination client, which is only another one when the clients are in
+// different shards
+fn destination<'a>(
+    source: &'a mut TransactionProcessor,
+    other: &'a mut Option<&mut TransactionProcessor>,
+) -> &'a mut TransactionProcessor {
+    match other {
+        Some(processor) => processor,
+        None => source,
+    }
+}
+
+impl TransactionProcessor {
+    // Both sides are validated before either of them is committed, so a transfer rejected by one of
+    // the clients leaves both accounts untouched
+    pub(crate) fn process_client_transfer(
+        &mut self,
+        mut other: Option<&mut TransactionProcessor>,
+        client_transfer: &ClientTransfer,
+    ) -> Result<(), ProcessingError> {
+        let transaction = Transaction::ClientTransfer(client_transfer.clone());
+        let source_id = client_transfer.client_id;
+        let destination_id = client_transfer.destination_client_id;
+        let observes_accounts = self.observes_accounts();
+        let source_before = self.accounts.get(&source_id).cloned().unwrap_or_default();
+        let destination_before = destination(self, &mut other)
+            .accounts
+            .get(&destination_id)
+            .cloned()
+            .unwrap_or_default();
+
+        let result =
+            match self.validate_client_transfer(other.as_deref(), &transaction, client_transfer) {
+                Ok((sent, received)) => {
+                    self.commit(sent);
+                    destination(self, &mut other).commit(received);
+                    Ok(None)
+                }
+                Err(error) => Err(error),
+            };
+        if observes_accounts {
+            // Rejections are only reported to the source client, who made the transfer
+            self.notify(&transaction, source_id, &source_before, &result);
+            if result.is_ok() {
+                destination(self, &mut other).notify(
+                    &transaction,
+                    destination_id,
+                    &destination_before,
+                    &result,
+                );
+            }
+        }
+        result?;
+
+        self.applied_transactions += 1;
+        self.advance_clock(&transaction);
+        if let Some(other) = other {
+            other.advance_clock(&transaction);
+        }
+        Ok(())
+    }
+
+    fn validate_client_transfer(
+        &self,
+        other: Option<&TransactionProcessor>,
+        transaction: &Transaction,
+        client_transfer: &ClientTransfer,
+    ) -> Result<(Change, Change), ProcessingError> {
+        self.check_timestamp(transaction)?;
+        if let Some(other) = other {
+            other.check_timestamp(transaction)?;
+        }
+        let sent = self.validate_sent(client_transfer)?;
+        let received = other.unwrap_or(self).validate_received(client_transfer)?;
+        Ok((sent, received))
+    }
+
+    fn validate_sent(&self, client_transfer: &ClientTransfer) -> Result<Change, ProcessingError> {
+        if self.is_known_transaction(client_transfer.transaction_id) {
+            return Err(ProcessingError::TransactionIdAlreadyExists);
+        }
+        let client_account = self
+            .accounts
+            .get(&client_transfer.client_id)
+            .cloned()
+            .unwrap_or_default();
+        if client_account.locked {
+            return Err(ProcessingError::TransferOnLockedAccount);
+        }
+        if client_account.available < client_transfer.amount {
+            return Err(ProcessingError::NotEnoughMoneyForWithdrawal);
+        }
+        Ok(Change {
+            postings: vec![Posting::new(
+                LedgerAccount::Available(client_transfer.client_id),
+                LedgerAccount::TransfersClearing,
+                client_transfer.amount,
+            )],
+            client_transfer: Some(client_transfer.clone()),
+            ..Change::new(
+                client_transfer.client_id,
+                client_transfer.transaction_id,
+                false,
+            )
+        })
+    }
+
+    // Checks the transaction ID again, as the destination shard keeps its own transfers
+    fn validate_received(
+        &self,
+        client_transfer: &ClientTransfer,
+    ) -> Result<Change, ProcessingError> {
+        if self.is_known_transaction(client_transfer.transaction_id) {
+            return Err(ProcessingError::TransactionIdAlreadyExists);
+        }
+        let destination_id = client_transfer.destination_client_id;
+        if self
+            .accounts
+            .get(&destination_id)
+            .is_some_and(|account| account.locked)
+        {
+            return Err(ProcessingError::TransferToLockedAccount);
+        }
+        Ok(Change {
+            postings: vec![Posting::new(
+                LedgerAccount::TransfersClearing,
+                LedgerAccount::Available(destination_id),
+                client_transfer.amount,
+            )],
+            client_transfer: Some(client_transfer.clone()),
+            ..Change::new(destination_id, client_transfer.transaction_id, false)
+        })
+    }
+}
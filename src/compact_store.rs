@@ -0,0 +1,465 @@
+use std::collections::HashMap;
+use std::convert::TryInto;
+
+use rust_decimal::Decimal;
+
+use crate::{
+    ClientID, MemoryTransferStore, Timestamp, TransactionID, Transfer, TransferStore, TransferType,
+};
+
+// Transaction IDs are dense, so the transfers are kept in pages of a table directly indexed by the
+// ID. A page is only allocated once it has enough transfers and is dropped when it becomes empty
+// again.
+const PAGE_BITS: u32 = 12;
+const PAGE_SIZE: usize = 1 << PAGE_BITS;
+const PAGE_WORDS: usize = PAGE_SIZE / 64;
+
+// A page takes about 43 KB, which is as much as about 400 transfers kept in hash maps. Until a page
+// reaches this many transfers, they stay in the hash maps, so sparse IDs don't allocate a whole
+// page for every few transfers.
+const DENSE_PAGE_TRANSFERS: u32 = 512;
+
+// Amounts take 8 bytes: the scale in the upper 5 bits and the mantissa in the rest. The rare amounts
+// that don't fit are kept aside and marked by a value that is never a valid encoding.
+const SCALE_SHIFT: u32 = 59;
+const MANTISSA_MASK: u64 = (1 << SCALE_SHIFT) - 1;
+const LARGE_AMOUNT: u64 = u64::MAX;
+
+fn encode_amount(amount: Decimal) -> Option<u64> {
+    if amount.is_sign_negative() {
+        return None;
+    }
+    let bytes = amount.serialize();
+    let mantissa = u64::from_le_bytes(bytes[4..12].try_into().unwrap());
+    if bytes[12..16] != [0; 4] || mantissa > MANTISSA_MASK {
+        return None;
+    }
+    Some((amount.scale() as u64) << SCALE_SHIFT | mantissa)
+}
+
+fn decode_amount(encoded: u64) -> Decimal {
+    Decimal::from_i128_with_scale(
+        (encoded & MANTISSA_MASK) as i128,
+        (encoded >> SCALE_SHIFT) as u32,
+    )
+}
+
+type Bits = [u64; PAGE_WORDS];
+
+fn bit(bits: &Bits, slot: usize) -> bool {
+    bits[slot / 64] & (1 << (slot % 64)) != 0
+}
+
+fn set_bit(bits: &mut Bits, slot: usize, value: bool) {
+    if value {
+        bits[slot / 64] |= 1 << (slot % 64);
+    } else {
+        bits[slot / 64] &= !(1 << (slot % 64));
+    }
+}
+
+fn set_bits(bits: &Bits) -> impl Iterator<Item = usize> + '_ {
+    bits.iter().enumerate().flat_map(|(word_index, &word)| {
+        (0..64)
+            .filter(move |bit| word & (1 << bit) != 0)
+            .map(move |bit| word_index * 64 + bit)
+    })
+}
+
+struct Page {
+    present: Bits,
+    withdrawal: Bits,
+    disputed: Bits,
+    charged_back: Bits,
+    clients: Box<[u16]>,
+    amounts: Box<[u64]>,
+    // Allocated with the first timestamped transfer of the page
+    timestamps: Option<(Bits, Box<[u64]>)>,
+}
+
+impl Page {
+    fn new() -> Page {
+        Page {
+            present: [0; PAGE_WORDS],
+            withdrawal: [0; PAGE_WORDS],
+            disputed: [0; PAGE_WORDS],
+            charged_back: [0; PAGE_WORDS],
+            clients: vec![0; PAGE_SIZE].into_boxed_slice(),
+            amounts: vec![0; PAGE_SIZE].into_boxed_slice(),
+            timestamps: None,
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.present
+            .iter()
+            .chain(&self.disputed)
+            .chain(&self.charged_back)
+            .all(|word| *word == 0)
+    }
+}
+
+// Disputes and chargebacks are bits, and only the partial ones remember their amounts separately
+#[derive(Default)]
+struct DensePages {
+    pages: Vec<Option<Box<Page>>>,
+    large_amounts: HashMap<TransactionID, Decimal>,
+    partially_disputed: HashMap<TransactionID, Decimal>,
+    partially_charged_back: HashMap<TransactionID, Decimal>,
+}
+
+fn location(transaction_id: TransactionID) -> (usize, usize) {
+    let id = transaction_id.id as usize;
+    (id >> PAGE_BITS, id & (PAGE_SIZE - 1))
+}
+
+impl DensePages {
+    fn has_page(&self, transaction_id: TransactionID) -> bool {
+        self.page(transaction_id).is_some()
+    }
+
+    fn page(&self, transaction_id: TransactionID) -> Option<(&Page, usize)> {
+        let (page_index, slot) = location(transaction_id);
+        let page = self.pages.get(page_index)?.as_deref()?;
+        Some((page, slot))
+    }
+
+    fn existing_page_mut(&mut self, transaction_id: TransactionID) -> Option<(&mut Page, usize)> {
+        let (page_index, slot) = location(transaction_id);
+        let page = self.pages.get_mut(page_index)?.as_deref_mut()?;
+        Some((page, slot))
+    }
+
+    fn page_mut(&mut self, transaction_id: TransactionID) -> (&mut Page, usize) {
+        let (page_index, slot) = location(transaction_id);
+        if self.pages.len() <= page_index {
+            self.pages.resize_with(page_index + 1, || None);
+        }
+        let page = self.pages[page_index].get_or_insert_with(|| Box::new(Page::new()));
+        (page, slot)
+    }
+
+    fn drop_page_if_empty(&mut self, transaction_id: TransactionID) {
+        let (page_index, _) = location(transaction_id);
+        if let Some(page) = self.pages.get_mut(page_index) {
+            if page.as_ref().is_some_and(|page| page.is_empty()) {
+                *page = None;
+            }
+        }
+    }
+
+    fn transfer_at(&self, transaction_id: TransactionID, page: &Page, slot: usize) -> Transfer {
+        Transfer {
+            transfer_type: if bit(&page.withdrawal, slot) {
+                TransferType::Withdrawal
+            } else {
+                TransferType::Deposit
+            },
+            client_id: ClientID::new(page.clients[slot]),
+            transaction_id,
+            amount: match page.amounts[slot] {
+                LARGE_AMOUNT => self.large_amounts[&transaction_id],
+                encoded => decode_amount(encoded),
+            },
+            timestamp: match &page.timestamps {
+                Some((present, timestamps)) if bit(present, slot) => {
+                    Some(Timestamp::from_millis(timestamps[slot]))
+                }
+                _ => None,
+            },
+        }
+    }
+
+    // Disputed and charged back amounts equal to the entire transfer are the common case and take
+    // no space besides the bit
+    fn marked_amount(
+        &self,
+        transaction_id: TransactionID,
+        marks: fn(&Page) -> &Bits,
+        partial_amounts: &HashMap<TransactionID, Decimal>,
+    ) -> Option<Decimal> {
+        let (page, slot) = self.page(transaction_id)?;
+        if !bit(marks(page), slot) {
+            return None;
+        }
+        match partial_amounts.get(&transaction_id) {
+            Some(amount) => Some(*amount),
+            None => self
+                .transfer(transaction_id)
+                .map(|transfer| transfer.amount),
+        }
+    }
+
+    fn marked_amounts<'a>(
+        &'a self,
+        marks: fn(&Page) -> &Bits,
+        partial_amounts: &'a HashMap<TransactionID, Decimal>,
+    ) -> Box<dyn Iterator<Item = (TransactionID, Decimal)> + 'a> {
+        Box::new(self.slots(marks).filter_map(move |transaction_id| {
+            let amount = self.marked_amount(transaction_id, marks, partial_amounts)?;
+            Some((transaction_id, amount))
+        }))
+    }
+
+    fn slots(&self, marks: fn(&Page) -> &Bits) -> impl Iterator<Item = TransactionID> + '_ {
+        self.pages
+            .iter()
+            .enumerate()
+            .filter_map(|(page_index, page)| Some((page_index, page.as_deref()?)))
+            .flat_map(move |(page_index, page)| {
+                set_bits(marks(page))
+                    .map(move |slot| TransactionID::new(((page_index << PAGE_BITS) | slot) as u32))
+            })
+    }
+}
+
+impl TransferStore for DensePages {
+    fn transfer(&self, transaction_id: TransactionID) -> Option<Transfer> {
+        let (page, slot) = self.page(transaction_id)?;
+        if !bit(&page.present, slot) {
+            return None;
+        }
+        Some(self.transfer_at(transaction_id, page, slot))
+    }
+
+    fn insert_transfer(&mut self, transfer: Transfer) {
+        let transaction_id = transfer.transaction_id;
+        let encoded_amount = encode_amount(transfer.amount);
+        let (page, slot) = self.page_mut(transaction_id);
+        set_bit(&mut page.present, slot, true);
+        set_bit(
+            &mut page.withdrawal,
+            slot,
+            transfer.transfer_type == TransferType::Withdrawal,
+        );
+        page.clients[slot] = transfer.client_id.id;
+        page.amounts[slot] = encoded_amount.unwrap_or(LARGE_AMOUNT);
+        match transfer.timestamp {
+            Some(timestamp) => {
+                let (present, timestamps) = page.timestamps.get_or_insert_with(|| {
+                    ([0; PAGE_WORDS], vec![0; PAGE_SIZE].into_boxed_slice())
+                });
+                set_bit(present, slot, true);
+                timestamps[slot] = timestamp.millis();
+            }
+            None => {
+                if let Some((present, _)) = page.timestamps.as_mut() {
+                    set_bit(present, slot, false);
+                }
+            }
+        }
+        match encoded_amount {
+            Some(_) => self.large_amounts.remove(&transaction_id),
+            None => self.large_amounts.insert(transaction_id, transfer.amount),
+        };
+    }
+
+    fn remove_transfer(&mut self, transaction_id: TransactionID) {
+        if let Some((page, slot)) = self.existing_page_mut(transaction_id) {
+            set_bit(&mut page.present, slot, false);
+            set_bit(&mut page.disputed, slot, false);
+            set_bit(&mut page.charged_back, slot, false);
+            self.drop_page_if_empty(transaction_id);
+        }
+        self.large_amounts.remove(&transaction_id);
+        self.partially_disputed.remove(&transaction_id);
+        self.partially_charged_back.remove(&transaction_id);
+    }
+
+    fn disputed_amount(&self, transaction_id: TransactionID) -> Option<Decimal> {
+        self.marked_amount(
+            transaction_id,
+            |page| &page.disputed,
+            &self.partially_disputed,
+        )
+    }
+
+    fn set_disputed_amount(&mut self, transaction_id: TransactionID, amount: Option<Decimal>) {
+        let transfer_amount = self
+            .transfer(transaction_id)
+            .map(|transfer| transfer.amount);
+        match amount {
+            Some(amount) => {
+                let (page, slot) = self.page_mut(transaction_id);
+                set_bit(&mut page.disputed, slot, true);
+                if Some(amount) == transfer_amount {
+                    self.partially_disputed.remove(&transaction_id);
+                } else {
+                    self.partially_disputed.insert(transaction_id, amount);
+                }
+            }
+            None => {
+                if let Some((page, slot)) = self.existing_page_mut(transaction_id) {
+                    set_bit(&mut page.disputed, slot, false);
+                    self.drop_page_if_empty(transaction_id);
+                }
+                self.partially_disputed.remove(&transaction_id);
+            }
+        }
+    }
+
+    fn charged_back_amount(&self, transaction_id: TransactionID) -> Option<Decimal> {
+        self.marked_amount(
+            transaction_id,
+            |page| &page.charged_back,
+            &self.partially_charged_back,
+        )
+    }
+
+    fn set_charged_back_amount(&mut self, transaction_id: TransactionID, amount: Decimal) {
+        let transfer_amount = self
+            .transfer(transaction_id)
+            .map(|transfer| transfer.amount);
+        let (page, slot) = self.page_mut(transaction_id);
+        set_bit(&mut page.charged_back, slot, true);
+        if Some(amount) == transfer_amount {
+            self.partially_charged_back.remove(&transaction_id);
+        } else {
+            self.partially_charged_back.insert(transaction_id, amount);
+        }
+    }
+
+    fn transfers(&self) -> Box<dyn Iterator<Item = Transfer> + '_> {
+        Box::new(
+            self.slots(|page| &page.present)
+                .filter_map(move |transaction_id| self.transfer(transaction_id)),
+        )
+    }
+
+    fn disputed_amounts(&self) -> Box<dyn Iterator<Item = (TransactionID, Decimal)> + '_> {
+        self.marked_amounts(|page| &page.disputed, &self.partially_disputed)
+    }
+
+    fn charged_back_amounts(&self) -> Box<dyn Iterator<Item = (TransactionID, Decimal)> + '_> {
+        self.marked_amounts(|page| &page.charged_back, &self.partially_charged_back)
+    }
+}
+
+// Memory-optimised store taking about 11 bytes per transfer, plus 8 more for a timestamp, as long
+// as the transaction IDs are dense
+#[derive(Default)]
+pub struct CompactTransferStore {
+    dense: DensePages,
+    sparse: MemoryTransferStore,
+    // Number of transfers kept in the sparse store by the index of the page they belong to
+    sparse_transfers: HashMap<usize, u32>,
+}
+
+impl CompactTransferStore {
+    fn store(&self, transaction_id: TransactionID) -> &dyn TransferStore {
+        if self.dense.has_page(transaction_id) {
+            &self.dense
+        } else {
+            &self.sparse
+        }
+    }
+
+    fn store_mut(&mut self, transaction_id: TransactionID) -> &mut dyn TransferStore {
+        if self.dense.has_page(transaction_id) {
+            &mut self.dense
+        } else {
+            &mut self.sparse
+        }
+    }
+
+    // Moves the transfers of the page, together with their dispute and chargeback state, from the
+    // sparse store to a newly allocated page
+    fn allocate_page(&mut self, page_index: usize) {
+        self.sparse_transfers.remove(&page_index);
+        let first_id = page_index << PAGE_BITS;
+        for id in first_id..first_id + PAGE_SIZE {
+            let transaction_id = TransactionID::new(id as u32);
+            let transfer = match self.sparse.transfer(transaction_id) {
+                Some(transfer) => transfer,
+                None => continue,
+            };
+            let disputed_amount = self.sparse.disputed_amount(transaction_id);
+            let charged_back_amount = self.sparse.charged_back_amount(transaction_id);
+            self.sparse.remove_transfer(transaction_id);
+            self.dense.insert_transfer(transfer);
+            self.dense
+                .set_disputed_amount(transaction_id, disputed_amount);
+            if let Some(amount) = charged_back_amount {
+                self.dense.set_charged_back_amount(transaction_id, amount);
+            }
+        }
+    }
+}
+
+impl TransferStore for CompactTransferStore {
+    fn transfer(&self, transaction_id: TransactionID) -> Option<Transfer> {
+        self.store(transaction_id).transfer(transaction_id)
+    }
+
+    fn insert_transfer(&mut self, transfer: Transfer) {
+        let transaction_id = transfer.transaction_id;
+        if self.dense.has_page(transaction_id) {
+            return self.dense.insert_transfer(transfer);
+        }
+        let is_new = self.sparse.transfer(transaction_id).is_none();
+        self.sparse.insert_transfer(transfer);
+        if is_new {
+            let (page_index, _) = location(transaction_id);
+            let transfers = self.sparse_transfers.entry(page_index).or_default();
+            *transfers += 1;
+            if *transfers >= DENSE_PAGE_TRANSFERS {
+                self.allocate_page(page_index);
+            }
+        }
+    }
+
+    fn remove_transfer(&mut self, transaction_id: TransactionID) {
+        if self.dense.has_page(transaction_id) {
+            return self.dense.remove_transfer(transaction_id);
+        }
+        if self.sparse.transfer(transaction_id).is_some() {
+            let (page_index, _) = location(transaction_id);
+            if let Some(transfers) = self.sparse_transfers.get_mut(&page_index) {
+                *transfers -= 1;
+                if *transfers == 0 {
+                    self.sparse_transfers.remove(&page_index);
+                }
+            }
+        }
+        self.sparse.remove_transfer(transaction_id);
+    }
+
+    fn disputed_amount(&self, transaction_id: TransactionID) -> Option<Decimal> {
+        self.store(transaction_id).disputed_amount(transaction_id)
+    }
+
+    fn set_disputed_amount(&mut self, transaction_id: TransactionID, amount: Option<Decimal>) {
+        self.store_mut(transaction_id)
+            .set_disputed_amount(transaction_id, amount)
+    }
+
+    fn charged_back_amount(&self, transaction_id: TransactionID) -> Option<Decimal> {
+        self.store(transaction_id)
+            .charged_back_amount(transaction_id)
+    }
+
+    fn set_charged_back_amount(&mut self, transaction_id: TransactionID, amount: Decimal) {
+        self.store_mut(transaction_id)
+            .set_charged_back_amount(transaction_id, amount)
+    }
+
+    fn transfers(&self) -> Box<dyn Iterator<Item = Transfer> + '_> {
+        Box::new(self.dense.transfers().chain(self.sparse.transfers()))
+    }
+
+    fn disputed_amounts(&self) -> Box<dyn Iterator<Item = (TransactionID, Decimal)> + '_> {
+        Box::new(
+            self.dense
+                .disputed_amounts()
+                .chain(self.sparse.disputed_amounts()),
+        )
+    }
+
+    fn charged_back_amounts(&self) -> Box<dyn Iterator<Item = (TransactionID, Decimal)> + '_> {
+        Box::new(
+            self.dense
+                .charged_back_amounts()
+                .chain(self.sparse.charged_back_amounts()),
+        )
+    }
+}
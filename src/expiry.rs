@@ -0,0 +1,124 @@
+use std::convert::TryFrom;
+
+use log::warn;
+
+use crate::{
+    AmendmentType, OutOfOrderPolicy, ProcessingError, Timestamp, Transaction, TransactionID,
+    TransactionProcessor, Transfer,
+};
+
+// Transfers are forgotten once they are older than the dispute window, compared to the latest
+// timestamp seen so far. Only their IDs are kept, so that disputes of them are reported as expired
+// and the IDs can't be reused.
+impl TransactionProcessor {
+    fn dispute_window_millis(&self) -> Option<u64> {
+        self.config
+            .dispute_window
+            .map(|window| u64::try_from(window.as_millis()).unwrap_or(u64::MAX))
+    }
+
+    // Disputes without a timestamp are checked against the latest timestamp seen so far
+    pub(crate) fn is_expired(&self, transfer: &Transfer, now: Option<Timestamp>) -> bool {
+        match (
+            self.dispute_window_millis(),
+            transfer.timestamp,
+            now.or(self.clock),
+        ) {
+            (Some(window), Some(timestamp), Some(now)) => {
+                now.millis() > timestamp.millis().saturating_add(window)
+            }
+            _ => false,
+        }
+    }
+
+    pub(crate) fn check_timestamp(&self, transaction: &Transaction) -> Result<(), ProcessingError> {
+        let (timestamp, clock) = match (transaction.timestamp(), self.clock) {
+            (Some(timestamp), Some(clock)) if timestamp < clock => (timestamp, clock),
+            _ => return Ok(()),
+        };
+        match self.config.out_of_order {
+            OutOfOrderPolicy::Accept => Ok(()),
+            OutOfOrderPolicy::Warn => {
+                warn!(
+                    "[ {} ] is {} ms older than the latest transaction",
+                    transaction,
+                    clock.millis() - timestamp.millis()
+                );
+                Ok(())
+            }
+            OutOfOrderPolicy::Reject => Err(ProcessingError::TimestampOutOfOrder),
+        }
+    }
+
+    pub(crate) fn advance_clock(&mut self, transaction: &Transaction) {
+        if let Some(timestamp) = transaction.timestamp() {
+            self.clock = self.clock.max(Some(timestamp));
+        }
+        let window = match self.dispute_window_millis() {
+            Some(window) => window,
+            None => return,
+        };
+
+        match transaction {
+            Transaction::Transfer(transfer) => {
+                if let Some(timestamp) = transfer.timestamp {
+                    self.expiry_queue
+                        .push_back((timestamp, transfer.transaction_id));
+                }
+            }
+            // Transfers that were in dispute when they expired are forgotten once settled
+            Transaction::Amendment(amendment)
+                if amendment.amendment_type != AmendmentType::Dispute =>
+            {
+                let expired = self
+                    .store
+                    .transfer(amendment.transaction_id)
+                    .is_some_and(|transfer| self.is_expired(&transfer, None));
+                if expired {
+                    self.evict(amendment.transaction_id);
+                }
+            }
+            _ => {}
+        }
+
+        let clock = match self.clock {
+            Some(clock) => clock,
+            None => return,
+        };
+        while let Some(&(timestamp, transaction_id)) = self.expiry_queue.front() {
+            if clock.millis() <= timestamp.millis().saturating_add(window) {
+                break;
+            }
+            self.expiry_queue.pop_front();
+            if self.store.disputed_amount(transaction_id).is_none() {
+                self.evict(transaction_id);
+            }
+        }
+    }
+
+    fn evict(&mut self, transaction_id: TransactionID) {
+        if self.store.transfer(transaction_id).is_some() {
+            self.store.remove_transfer(transaction_id);
+            self.expired.insert(transaction_id);
+        }
+    }
+
+    // The queue isn't a part of the saved state, it's restored from the stored transfers
+    pub(crate) fn rebuild_expiry_queue(&mut self) {
+        self.expiry_queue.clear();
+        if self.config.dispute_window.is_none() {
+            return;
+        }
+        let mut queue = self
+            .store
+            .transfers()
+            .filter_map(|transfer| Some((transfer.timestamp?, transfer.transaction_id)))
+            .collect::<Vec<_>>();
+        queue.sort_unstable_by_key(|(timestamp, _)| *timestamp);
+        self.expiry_queue = queue.into();
+    }
+
+    pub fn expired_transfers(&self) -> usize {
+        self.expired.len()
+    }
+}
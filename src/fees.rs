@@ -0,0 +1,74 @@
+use rust_decimal::{prelude::Zero, Decimal, RoundingStrategy};
+
+use crate::{ClientID, TransactionProcessor, TransferType};
+
+// Percentage fees are rounded to the four decimal places of the amounts
+const FEE_DECIMAL_PLACES: u32 = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FeeRate {
+    Flat(Decimal),
+    // Percent of the amount the fee is charged for
+    Percentage(Decimal),
+}
+
+impl FeeRate {
+    pub fn fee(&self, amount: Decimal) -> Decimal {
+        match self {
+            FeeRate::Flat(fee) => *fee,
+            FeeRate::Percentage(percent) => (amount * percent / Decimal::from(100))
+                .round_dp_with_strategy(FEE_DECIMAL_PLACES, RoundingStrategy::MidpointAwayFromZero),
+        }
+    }
+}
+
+// Either a flat amount, e.g. `0.5`, or a percentage, e.g. `1.5%`
+impl std::str::FromStr for FeeRate {
+    type Err = String;
+
+    fn from_str(rate: &str) -> Result<FeeRate, String> {
+        let (amount, percentage) = match rate.strip_suffix('%') {
+            Some(percent) => (percent, true),
+            None => (rate, false),
+        };
+        match amount.trim().parse::<Decimal>() {
+            Ok(amount) if amount >= Decimal::zero() && percentage => {
+                Ok(FeeRate::Percentage(amount))
+            }
+            Ok(amount) if amount >= Decimal::zero() => Ok(FeeRate::Flat(amount)),
+            _ => Err(format!("Invalid fee {}", rate)),
+        }
+    }
+}
+
+// Fees charged automatically on top of the transactions, paid from the available funds of the client
+// to the house account of the ledger
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FeeSchedule {
+    pub deposit: Option<FeeRate>,
+    pub withdrawal: Option<FeeRate>,
+    // Charged for the amount of every chargeback
+    pub chargeback: Option<FeeRate>,
+}
+
+impl FeeSchedule {
+    pub fn transfer_fee(&self, transfer_type: TransferType, amount: Decimal) -> Decimal {
+        let rate = match transfer_type {
+            TransferType::Deposit => self.deposit,
+            TransferType::Withdrawal => self.withdrawal,
+        };
+        rate.map_or(Decimal::zero(), |rate| rate.fee(amount))
+    }
+
+    pub fn chargeback_fee(&self, amount: Decimal) -> Decimal {
+        self.chargeback
+            .map_or(Decimal::zero(), |rate| rate.fee(amount))
+    }
+}
+
+impl TransactionProcessor {
+    // Both the fees of the schedule and the fee transactions
+    pub fn fees_charged(&self, client_id: ClientID) -> Decimal {
+        self.fees.get(&client_id).copied().unwrap_or_default()
+    }
+}
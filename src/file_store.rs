@@ -0,0 +1,363 @@
+use std::convert::TryInto;
+
+use rust_decimal::Decimal;
+
+use crate::{ClientID, Timestamp, TransactionID, Transfer, TransferStore, TransferType};
+
+// Every transaction ID owns a fixed-size record at `id * RECORD_SIZE`, so any transfer is found with
+// a single read. IDs that were never used are holes in a sparse file and don't take disk space.
+//
+// Record layout, all integers are little-endian:
+//   0       flags
+//   1..3    client ID
+//   3..19   amount
+//   19..27  timestamp
+//   27..43  disputed amount
+//   43..59  charged back amount
+const RECORD_SIZE: usize = 64;
+const SCAN_RECORDS: usize = 4096;
+
+// The undo log next to the store starts with the committed generation, followed by the previous
+// contents of every record changed since, as entries of the transaction ID and the record. Rolling
+// the entries back in reverse order leaves every record as it was at the commit.
+const GENERATION_SIZE: u64 = 8;
+const UNDO_ENTRY_SIZE: usize = 4 + RECORD_SIZE;
+
+const PRESENT: u8 = 1;
+const WITHDRAWAL: u8 = 1 << 1;
+const HAS_TIMESTAMP: u8 = 1 << 2;
+const DISPUTED: u8 = 1 << 3;
+const CHARGED_BACK: u8 = 1 << 4;
+
+type Record = [u8; RECORD_SIZE];
+
+fn decimal_at(record: &Record, offset: usize) -> Decimal {
+    Decimal::deserialize(record[offset..offset + 16].try_into().unwrap())
+}
+
+fn transfer_from_record(transaction_id: TransactionID, record: &Record) -> Option<Transfer> {
+    if record[0] & PRESENT == 0 {
+        return None;
+    }
+    Some(Transfer {
+        transfer_type: if record[0] & WITHDRAWAL != 0 {
+            TransferType::Withdrawal
+        } else {
+            TransferType::Deposit
+        },
+        client_id: ClientID::new(u16::from_le_bytes([record[1], record[2]])),
+        transaction_id,
+        amount: decimal_at(record, 3),
+        timestamp: if record[0] & HAS_TIMESTAMP != 0 {
+            Some(Timestamp::from_millis(u64::from_le_bytes(
+                record[19..27].try_into().unwrap(),
+            )))
+        } else {
+            None
+        },
+    })
+}
+
+fn open_for_update(filepath: &std::path::Path) -> std::io::Result<std::fs::File> {
+    std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(filepath)
+}
+
+// Transfers are kept in a file with direct indexing by the transaction ID. Failing to read or to
+// write the file panics, as the processor can't go on with an unknown state of the transfers.
+pub struct FileTransferStore {
+    file: std::fs::File,
+    undo_log: std::fs::File,
+    undo_log_len: u64,
+    generation: u64,
+}
+
+impl FileTransferStore {
+    // The undo log is kept in the same directory, with `.undo` appended to the name of the store
+    pub fn open(filepath: &std::path::Path) -> Result<FileTransferStore, std::io::Error> {
+        let file = open_for_update(filepath)?;
+        let mut undo_log_path = filepath.as_os_str().to_owned();
+        undo_log_path.push(".undo");
+        let undo_log = open_for_update(std::path::Path::new(&undo_log_path))?;
+
+        let undo_log_len = undo_log.metadata()?.len();
+        let mut generation = [0; GENERATION_SIZE as usize];
+        if undo_log_len < GENERATION_SIZE {
+            write_all_at(&undo_log, &generation, 0)?;
+        } else {
+            read_exact_at(&undo_log, &mut generation, 0)?;
+        }
+        // A torn entry at the end was never followed by the write of its record
+        let entries = undo_log_len.saturating_sub(GENERATION_SIZE) / UNDO_ENTRY_SIZE as u64;
+        Ok(FileTransferStore {
+            file,
+            undo_log,
+            undo_log_len: GENERATION_SIZE + entries * UNDO_ENTRY_SIZE as u64,
+            generation: u64::from_le_bytes(generation),
+        })
+    }
+
+    fn offset(transaction_id: TransactionID) -> u64 {
+        transaction_id.id as u64 * RECORD_SIZE as u64
+    }
+
+    // Reads as much as available, the part past the end of the file stays zeroed
+    fn read_at(&self, buffer: &mut [u8], offset: u64) -> usize {
+        let mut read = 0;
+        while read < buffer.len() {
+            match read_at(&self.file, &mut buffer[read..], offset + read as u64) {
+                Ok(0) => break,
+                Ok(bytes) => read += bytes,
+                Err(err) if err.kind() == std::io::ErrorKind::Interrupted => {}
+                Err(err) => panic!("Failed to read the transfer store: {}", err),
+            }
+        }
+        read
+    }
+
+    fn record(&self, transaction_id: TransactionID) -> Record {
+        let mut record = [0; RECORD_SIZE];
+        self.read_at(&mut record, FileTransferStore::offset(transaction_id));
+        record
+    }
+
+    // The previous contents of the record go to the undo log before the record is changed
+    fn update_record(&mut self, transaction_id: TransactionID, update: impl FnOnce(&mut Record)) {
+        let mut record = self.record(transaction_id);
+        let mut undo_entry = [0; UNDO_ENTRY_SIZE];
+        undo_entry[..4].copy_from_slice(&transaction_id.id.to_le_bytes());
+        undo_entry[4..].copy_from_slice(&record);
+        write_all_at(&self.undo_log, &undo_entry, self.undo_log_len)
+            .unwrap_or_else(|err| panic!("Failed to write the transfer store undo log: {}", err));
+        self.undo_log_len += UNDO_ENTRY_SIZE as u64;
+
+        update(&mut record);
+        self.write_record(transaction_id, &record);
+    }
+
+    fn write_record(&self, transaction_id: TransactionID, record: &Record) {
+        write_all_at(
+            &self.file,
+            record,
+            FileTransferStore::offset(transaction_id),
+        )
+        .unwrap_or_else(|err| panic!("Failed to write the transfer store: {}", err));
+    }
+
+    fn roll_back(&mut self) -> std::io::Result<()> {
+        let entries = (self.undo_log_len - GENERATION_SIZE) / UNDO_ENTRY_SIZE as u64;
+        for index in (0..entries).rev() {
+            let mut undo_entry = [0; UNDO_ENTRY_SIZE];
+            read_exact_at(
+                &self.undo_log,
+                &mut undo_entry,
+                GENERATION_SIZE + index * UNDO_ENTRY_SIZE as u64,
+            )?;
+            let transaction_id =
+                TransactionID::new(u32::from_le_bytes(undo_entry[..4].try_into().unwrap()));
+            self.write_record(transaction_id, undo_entry[4..].try_into().unwrap());
+        }
+        self.file.sync_data()?;
+        self.clear_undo_log()
+    }
+
+    fn clear_undo_log(&mut self) -> std::io::Result<()> {
+        self.undo_log.set_len(GENERATION_SIZE)?;
+        self.undo_log.sync_data()?;
+        self.undo_log_len = GENERATION_SIZE;
+        Ok(())
+    }
+
+    fn records(&self) -> impl Iterator<Item = (TransactionID, Record)> + '_ {
+        let mut buffer = vec![0; RECORD_SIZE * SCAN_RECORDS];
+        let mut first_id = 0u64;
+        let mut records = 0..0;
+        std::iter::from_fn(move || loop {
+            if let Some(index) = records.next() {
+                let start = index * RECORD_SIZE;
+                let record: Record = buffer[start..start + RECORD_SIZE].try_into().unwrap();
+                let transaction_id = TransactionID::new((first_id + index as u64) as u32);
+                return Some((transaction_id, record));
+            }
+            if records.end > 0 {
+                first_id += records.end as u64;
+            }
+            let read = self.read_at(&mut buffer, first_id * RECORD_SIZE as u64);
+            if read < RECORD_SIZE {
+                return None;
+            }
+            records = 0..read / RECORD_SIZE;
+        })
+    }
+}
+
+fn write_all_at(file: &std::fs::File, buffer: &[u8], offset: u64) -> std::io::Result<()> {
+    let mut written = 0;
+    while written < buffer.len() {
+        match write_at(file, &buffer[written..], offset + written as u64) {
+            Ok(0) => return Err(std::io::ErrorKind::WriteZero.into()),
+            Ok(bytes) => written += bytes,
+            Err(err) if err.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(())
+}
+
+fn read_exact_at(file: &std::fs::File, buffer: &mut [u8], offset: u64) -> std::io::Result<()> {
+    let mut read = 0;
+    while read < buffer.len() {
+        match read_at(file, &mut buffer[read..], offset + read as u64) {
+            Ok(0) => return Err(std::io::ErrorKind::UnexpectedEof.into()),
+            Ok(bytes) => read += bytes,
+            Err(err) if err.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn read_at(file: &std::fs::File, buffer: &mut [u8], offset: u64) -> std::io::Result<usize> {
+    std::os::unix::fs::FileExt::read_at(file, buffer, offset)
+}
+
+#[cfg(unix)]
+fn write_at(file: &std::fs::File, buffer: &[u8], offset: u64) -> std::io::Result<usize> {
+    std::os::unix::fs::FileExt::write_at(file, buffer, offset)
+}
+
+#[cfg(windows)]
+fn read_at(file: &std::fs::File, buffer: &mut [u8], offset: u64) -> std::io::Result<usize> {
+    std::os::windows::fs::FileExt::seek_read(file, buffer, offset)
+}
+
+#[cfg(windows)]
+fn write_at(file: &std::fs::File, buffer: &[u8], offset: u64) -> std::io::Result<usize> {
+    std::os::windows::fs::FileExt::seek_write(file, buffer, offset)
+}
+
+impl TransferStore for FileTransferStore {
+    fn transfer(&self, transaction_id: TransactionID) -> Option<Transfer> {
+        transfer_from_record(transaction_id, &self.record(transaction_id))
+    }
+
+    fn insert_transfer(&mut self, transfer: Transfer) {
+        self.update_record(transfer.transaction_id, |record| {
+            record[0] = PRESENT;
+            if transfer.transfer_type == TransferType::Withdrawal {
+                record[0] |= WITHDRAWAL;
+            }
+            record[1..3].copy_from_slice(&transfer.client_id.id.to_le_bytes());
+            record[3..19].copy_from_slice(&transfer.amount.serialize());
+            if let Some(timestamp) = transfer.timestamp {
+                record[0] |= HAS_TIMESTAMP;
+                record[19..27].copy_from_slice(&timestamp.millis().to_le_bytes());
+            }
+        });
+    }
+
+    fn remove_transfer(&mut self, transaction_id: TransactionID) {
+        self.update_record(transaction_id, |record| *record = [0; RECORD_SIZE]);
+    }
+
+    fn disputed_amount(&self, transaction_id: TransactionID) -> Option<Decimal> {
+        let record = self.record(transaction_id);
+        if record[0] & DISPUTED != 0 {
+            Some(decimal_at(&record, 27))
+        } else {
+            None
+        }
+    }
+
+    fn set_disputed_amount(&mut self, transaction_id: TransactionID, amount: Option<Decimal>) {
+        self.update_record(transaction_id, |record| match amount {
+            Some(amount) => {
+                record[0] |= DISPUTED;
+                record[27..43].copy_from_slice(&amount.serialize());
+            }
+            None => record[0] &= !DISPUTED,
+        });
+    }
+
+    fn charged_back_amount(&self, transaction_id: TransactionID) -> Option<Decimal> {
+        let record = self.record(transaction_id);
+        if record[0] & CHARGED_BACK != 0 {
+            Some(decimal_at(&record, 43))
+        } else {
+            None
+        }
+    }
+
+    fn set_charged_back_amount(&mut self, transaction_id: TransactionID, amount: Decimal) {
+        self.update_record(transaction_id, |record| {
+            record[0] |= CHARGED_BACK;
+            record[43..59].copy_from_slice(&amount.serialize());
+        });
+    }
+
+    fn transfers(&self) -> Box<dyn Iterator<Item = Transfer> + '_> {
+        Box::new(
+            self.records().filter_map(|(transaction_id, record)| {
+                transfer_from_record(transaction_id, &record)
+            }),
+        )
+    }
+
+    fn disputed_amounts(&self) -> Box<dyn Iterator<Item = (TransactionID, Decimal)> + '_> {
+        Box::new(self.records().filter_map(|(transaction_id, record)| {
+            if record[0] & DISPUTED != 0 {
+                Some((transaction_id, decimal_at(&record, 27)))
+            } else {
+                None
+            }
+        }))
+    }
+
+    fn charged_back_amounts(&self) -> Box<dyn Iterator<Item = (TransactionID, Decimal)> + '_> {
+        Box::new(self.records().filter_map(|(transaction_id, record)| {
+            if record[0] & CHARGED_BACK != 0 {
+                Some((transaction_id, decimal_at(&record, 43)))
+            } else {
+                None
+            }
+        }))
+    }
+
+    fn is_persistent(&self) -> bool {
+        true
+    }
+
+    // The undo log goes first, so whatever reached the store can be rolled back
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.undo_log.sync_data()?;
+        self.file.sync_data()
+    }
+
+    fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    // The undo log is cleared before the new generation is written, so a commit interrupted in
+    // between leaves the previous generation with nothing to roll back
+    fn commit(&mut self, generation: u64) -> std::io::Result<()> {
+        self.flush()?;
+        self.clear_undo_log()?;
+        write_all_at(&self.undo_log, &generation.to_le_bytes(), 0)?;
+        self.undo_log.sync_data()?;
+        self.generation = generation;
+        Ok(())
+    }
+
+    fn restore(&mut self, generation: u64) -> std::io::Result<()> {
+        if generation == self.generation + 1 {
+            self.commit(generation)
+        } else {
+            self.roll_back()
+        }
+    }
+}
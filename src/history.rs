@@ -0,0 +1,121 @@
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    Account, AccountEvent, AccountEventType, ClientID, Timestamp, TransactionID,
+    TransactionProcessor,
+};
+
+#[derive(Debug, Clone, Copy, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum HistoryEventType {
+    Deposit,
+    Withdrawal,
+    Hold,
+    Release,
+    Chargeback,
+    Lock,
+    Unlock,
+    Sent,
+    Received,
+    Fee,
+}
+
+// Every entry keeps the balances of the account right after the event
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq, Eq)]
+pub struct HistoryEntry {
+    pub event_type: HistoryEventType,
+    pub transaction_id: TransactionID,
+    pub amount: Option<Decimal>,
+    pub timestamp: Option<Timestamp>,
+    pub account: Account,
+}
+
+impl HistoryEntry {
+    pub(crate) fn columns(&self) -> [String; 7] {
+        [
+            self.transaction_id.id.to_string(),
+            self.event_type.name().to_owned(),
+            self.amount
+                .map(|amount| amount.normalize().to_string())
+                .unwrap_or_default(),
+            self.account.available.normalize().to_string(),
+            self.account.held.normalize().to_string(),
+            self.account.total().normalize().to_string(),
+            self.account.locked.to_string(),
+        ]
+    }
+}
+
+impl HistoryEventType {
+    pub fn name(&self) -> &'static str {
+        match self {
+            HistoryEventType::Deposit => "deposit",
+            HistoryEventType::Withdrawal => "withdrawal",
+            HistoryEventType::Hold => "hold",
+            HistoryEventType::Release => "release",
+            HistoryEventType::Chargeback => "chargeback",
+            HistoryEventType::Lock => "lock",
+            HistoryEventType::Unlock => "unlock",
+            HistoryEventType::Sent => "sent",
+            HistoryEventType::Received => "received",
+            HistoryEventType::Fee => "fee",
+        }
+    }
+}
+
+// Flat representation for the statement output, the same shape in every format
+pub(crate) struct StatementRow<'a>(pub &'a HistoryEntry);
+
+impl Serialize for StatementRow<'_> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use serde::ser::SerializeStruct;
+
+        let entry = self.0;
+        let mut state = serializer.serialize_struct("StatementRow", 7)?;
+        state.serialize_field("tx", &entry.transaction_id)?;
+        state.serialize_field("type", &entry.event_type)?;
+        state.serialize_field("amount", &entry.amount.map(|amount| amount.normalize()))?;
+        state.serialize_field("available", &entry.account.available.normalize())?;
+        state.serialize_field("held", &entry.account.held.normalize())?;
+        state.serialize_field("total", &entry.account.total().normalize())?;
+        state.serialize_field("locked", &entry.account.locked)?;
+        state.end()
+    }
+}
+
+impl TransactionProcessor {
+    pub(crate) fn record_history(&mut self, event: &AccountEvent) {
+        let (event_type, amount) = match event.event_type {
+            AccountEventType::Deposited(amount) => (HistoryEventType::Deposit, Some(amount)),
+            AccountEventType::Withdrew(amount) => (HistoryEventType::Withdrawal, Some(amount)),
+            AccountEventType::FundsHeld(amount) => (HistoryEventType::Hold, Some(amount)),
+            AccountEventType::FundsReleased(amount) => (HistoryEventType::Release, Some(amount)),
+            AccountEventType::ChargedBack(amount) => (HistoryEventType::Chargeback, Some(amount)),
+            AccountEventType::AccountLocked => (HistoryEventType::Lock, None),
+            AccountEventType::AccountUnlocked => (HistoryEventType::Unlock, None),
+            AccountEventType::Sent(amount) => (HistoryEventType::Sent, Some(amount)),
+            AccountEventType::Received(amount) => (HistoryEventType::Received, Some(amount)),
+            AccountEventType::FeeCharged(amount) => (HistoryEventType::Fee, Some(amount)),
+            AccountEventType::Rejected(_) => return,
+        };
+        self.history
+            .entry(event.client_id)
+            .or_default()
+            .push(HistoryEntry {
+                event_type,
+                transaction_id: event.transaction.transaction_id(),
+                amount,
+                timestamp: event.transaction.timestamp(),
+                account: event.after.clone(),
+            });
+    }
+
+    // Only recorded with `ProcessorConfig::record_history`
+    pub fn history(&self, client_id: ClientID) -> &[HistoryEntry] {
+        self.history
+            .get(&client_id)
+            .map(Vec::as_slice)
+            .unwrap_or_default()
+    }
+}
@@ -0,0 +1,133 @@
+use std::convert::TryFrom;
+use std::sync::{Arc, Mutex};
+
+use log::{info, warn};
+use serde::Serialize;
+
+use crate::{
+    AccountWithClientID, ClientID, InputFormatError, ProcessingError, RawTransaction, Transaction,
+    TransactionProcessor,
+};
+
+pub fn status_code(err: &ProcessingError) -> u16 {
+    match err {
+        ProcessingError::TransferOnLockedAccount => 403,
+        ProcessingError::NotEnoughMoneyForWithdrawal => 422,
+        ProcessingError::NotEnoughMoneyForFee => 422,
+        ProcessingError::TryingToDisputeUnknownTransaction => 404,
+        ProcessingError::WrongClientInDispute => 403,
+        ProcessingError::TransferIsAlreadyInDispute => 409,
+        ProcessingError::ResolvedTransferWasNotInDispute => 409,
+        ProcessingError::ChargedBackTransferWasNotInDispute => 409,
+        ProcessingError::DisputingAlreadyChargedBackTransfer => 409,
+        ProcessingError::TransactionIdAlreadyExists => 409,
+        ProcessingError::UnlockingAccountThatIsNotLocked => 409,
+        ProcessingError::DisputedAmountExceedsTransfer => 422,
+        ProcessingError::DisputingWithdrawalIsNotAllowed => 403,
+        ProcessingError::DisputeWindowExpired => 409,
+        ProcessingError::TimestampOutOfOrder => 409,
+        ProcessingError::TransferToLockedAccount => 403,
+        ProcessingError::DisputingClientTransferIsNotAllowed => 403,
+        ProcessingError::FeeForUnknownClient => 404,
+        ProcessingError::DisputingFeeIsNotAllowed => 403,
+    }
+}
+
+#[derive(Serialize)]
+struct ErrorBody<'a> {
+    code: &'a str,
+    error: &'a str,
+}
+
+fn error_response(status: u16, code: &str, error: &str) -> (u16, String) {
+    (
+        status,
+        serde_json::to_string(&ErrorBody { code, error }).expect("Error body is serializable"),
+    )
+}
+
+fn account_response(processor: &TransactionProcessor, client_id: ClientID) -> (u16, String) {
+    match processor.accounts.get(&client_id) {
+        Some(account) => (
+            200,
+            serde_json::to_string(&AccountWithClientID {
+                client_id: &client_id,
+                account,
+            })
+            .expect("Account is serializable"),
+        ),
+        None => error_response(404, "E301", "UnknownClient"),
+    }
+}
+
+fn submit_transaction(processor: &Mutex<TransactionProcessor>, body: &[u8]) -> (u16, String) {
+    let transaction = match serde_json::from_slice::<RawTransaction>(body) {
+        Ok(raw_transaction) => Transaction::try_from(raw_transaction),
+        Err(err) => return input_error_response(&err.into()),
+    };
+    let transaction = match transaction {
+        Ok(transaction) => transaction,
+        Err(err) => return input_error_response(&err),
+    };
+
+    let mut processor = processor.lock().expect("Processor lock is poisoned");
+    match processor.process(&transaction) {
+        Ok(()) => account_response(&processor, transaction.client_id()),
+        Err(err) => error_response(status_code(&err), err.code(), err.name()),
+    }
+}
+
+fn input_error_response(err: &InputFormatError) -> (u16, String) {
+    error_response(400, err.code(), err.name())
+}
+
+// POST /transactions with a JSON transaction in the body applies it and replies with the
+// updated client account, GET /accounts/{client} replies with the client account
+pub fn handle(
+    processor: &Mutex<TransactionProcessor>,
+    method: &str,
+    url: &str,
+    body: &[u8],
+) -> (u16, String) {
+    let path = url.split('?').next().unwrap_or_default();
+    match (method, path) {
+        ("POST", "/transactions") => submit_transaction(processor, body),
+        ("GET", _) if path.starts_with("/accounts/") => {
+            match path["/accounts/".len()..].parse::<u16>() {
+                Ok(client_id) => account_response(
+                    &processor.lock().expect("Processor lock is poisoned"),
+                    ClientID::new(client_id),
+                ),
+                Err(_) => error_response(404, "E302", "NotFound"),
+            }
+        }
+        (_, "/transactions") => error_response(405, "E303", "MethodNotAllowed"),
+        (_, _) if path.starts_with("/accounts/") => error_response(405, "E303", "MethodNotAllowed"),
+        _ => error_response(404, "E302", "NotFound"),
+    }
+}
+
+pub fn serve_http(
+    processor: Arc<Mutex<TransactionProcessor>>,
+    listener: std::net::TcpListener,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let server = tiny_http::Server::from_listener(listener, None)?;
+    info!("Listening for HTTP on {}", server.server_addr());
+
+    let content_type = tiny_http::Header::from_bytes("Content-Type", "application/json")
+        .expect("Content-Type header is valid");
+    for mut request in server.incoming_requests() {
+        let mut body = Vec::new();
+        let (status, response_body) = match request.as_reader().read_to_end(&mut body) {
+            Ok(_) => handle(&processor, request.method().as_str(), request.url(), &body),
+            Err(_) => error_response(400, "E104", "JsonError"),
+        };
+        let response = tiny_http::Response::from_string(response_body)
+            .with_status_code(status)
+            .with_header(content_type.clone());
+        if let Err(err) = request.respond(response) {
+            warn!("Failed to respond: {:?}", err);
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,132 @@
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::TransactionID;
+
+// Transaction IDs are dense, so a set of them is a bit per ID, in pages allocated for the ranges of
+// IDs that have any. A page of 4096 IDs takes 512 bytes.
+const PAGE_BITS: u32 = 12;
+const PAGE_WORDS: usize = (1 << PAGE_BITS) / 64;
+
+type Page = [u64; PAGE_WORDS];
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct TransactionIdSet {
+    pages: Vec<Option<Box<Page>>>,
+    len: usize,
+}
+
+fn location(transaction_id: TransactionID) -> (usize, usize, u64) {
+    let id = transaction_id.id as usize;
+    let slot = id & ((1 << PAGE_BITS) - 1);
+    (id >> PAGE_BITS, slot / 64, 1 << (slot % 64))
+}
+
+impl TransactionIdSet {
+    pub(crate) fn contains(&self, transaction_id: TransactionID) -> bool {
+        let (page_index, word, mask) = location(transaction_id);
+        match self.pages.get(page_index) {
+            Some(Some(page)) => page[word] & mask != 0,
+            _ => false,
+        }
+    }
+
+    // Returns whether the ID wasn't in the set yet
+    pub(crate) fn insert(&mut self, transaction_id: TransactionID) -> bool {
+        let (page_index, word, mask) = location(transaction_id);
+        if self.pages.len() <= page_index {
+            self.pages.resize_with(page_index + 1, || None);
+        }
+        let page = self.pages[page_index].get_or_insert_with(|| Box::new([0; PAGE_WORDS]));
+        if page[word] & mask != 0 {
+            return false;
+        }
+        page[word] |= mask;
+        self.len += 1;
+        true
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.len
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub(crate) fn iter(&self) -> impl Iterator<Item = TransactionID> + '_ {
+        self.pages
+            .iter()
+            .enumerate()
+            .filter_map(|(page_index, page)| Some((page_index, page.as_ref()?)))
+            .flat_map(|(page_index, page)| {
+                page.iter()
+                    .enumerate()
+                    .flat_map(move |(word_index, &word)| {
+                        (0..64)
+                            .filter(move |bit| word & (1 << bit) != 0)
+                            .map(move |bit| {
+                                let slot = word_index * 64 + bit;
+                                TransactionID::new(((page_index << PAGE_BITS) | slot) as u32)
+                            })
+                    })
+            })
+    }
+
+    // Consecutive IDs as the first ID and the count
+    fn ranges(&self) -> impl Iterator<Item = (u32, u32)> + '_ {
+        let mut ids = self
+            .iter()
+            .map(|transaction_id| transaction_id.id)
+            .peekable();
+        std::iter::from_fn(move || {
+            let first = ids.next()?;
+            let mut count = 1;
+            while first
+                .checked_add(count)
+                .is_some_and(|next| ids.peek() == Some(&next))
+            {
+                ids.next();
+                count += 1;
+            }
+            Some((first, count))
+        })
+    }
+}
+
+impl Extend<TransactionID> for TransactionIdSet {
+    fn extend<Ids: IntoIterator<Item = TransactionID>>(&mut self, ids: Ids) {
+        for transaction_id in ids {
+            self.insert(transaction_id);
+        }
+    }
+}
+
+// Saved as ranges of consecutive IDs, single IDs as before the ranges
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum SavedIds {
+    Id(u32),
+    Range(u32, u32),
+}
+
+impl Serialize for TransactionIdSet {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(self.ranges())
+    }
+}
+
+impl<'de> Deserialize<'de> for TransactionIdSet {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<TransactionIdSet, D::Error> {
+        let mut set = TransactionIdSet::default();
+        for saved in Vec::<SavedIds>::deserialize(deserializer)? {
+            let (first, count) = match saved {
+                SavedIds::Id(id) => (id, 1),
+                SavedIds::Range(first, count) => (first, count),
+            };
+            for id in first..first.saturating_add(count) {
+                set.insert(TransactionID::new(id));
+            }
+        }
+        Ok(set)
+    }
+}
@@ -0,0 +1,251 @@
+use std::io::{BufRead, Seek, Write};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{ProcessingError, Transaction, TransactionProcessor};
+
+const DEFAULT_BATCH_SIZE: usize = 1000;
+
+#[derive(Debug)]
+pub enum JournalError {
+    InputMismatch {
+        journal_input: String,
+        input: String,
+    },
+    CorruptedEntry {
+        line: usize,
+    },
+    OutOfSequence {
+        expected: u64,
+        found: u64,
+    },
+    ReplayFailed {
+        sequence: u64,
+        error: ProcessingError,
+    },
+    IoError(std::io::Error),
+    JsonError(serde_json::Error),
+}
+
+impl std::convert::From<std::io::Error> for JournalError {
+    fn from(io_error: std::io::Error) -> JournalError {
+        JournalError::IoError(io_error)
+    }
+}
+
+impl std::convert::From<serde_json::Error> for JournalError {
+    fn from(json_error: serde_json::Error) -> JournalError {
+        JournalError::JsonError(json_error)
+    }
+}
+
+impl std::fmt::Display for JournalError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl std::error::Error for JournalError {}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct JournalPosition {
+    byte: u64,
+    line: u64,
+    record: u64,
+}
+
+impl std::convert::From<&csv::Position> for JournalPosition {
+    fn from(position: &csv::Position) -> JournalPosition {
+        JournalPosition {
+            byte: position.byte(),
+            line: position.line(),
+            record: position.record(),
+        }
+    }
+}
+
+impl std::convert::From<JournalPosition> for csv::Position {
+    fn from(position: JournalPosition) -> csv::Position {
+        let mut csv_position = csv::Position::new();
+        csv_position
+            .set_byte(position.byte)
+            .set_line(position.line)
+            .set_record(position.record);
+        csv_position
+    }
+}
+
+// Every entry is a single JSON line. `resume` is the input position right after the row the entry
+// was written for, so a rerun can continue from there.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "entry", rename_all = "lowercase")]
+enum JournalEntry {
+    Start {
+        input: String,
+    },
+    Applied {
+        sequence: u64,
+        resume: JournalPosition,
+        transaction: Transaction,
+    },
+    Checkpoint {
+        resume: JournalPosition,
+    },
+}
+
+pub struct Journal {
+    output: std::io::BufWriter<std::fs::File>,
+    batch_size: usize,
+    uncommitted: usize,
+    skipped_up_to: Option<csv::Position>,
+}
+
+impl Journal {
+    // Replays the journal on top of the processor and returns the position in the input the
+    // processing should resume from. Entries already included in the processor state, according
+    // to its count of applied transactions, are skipped.
+    pub fn open(
+        filepath: &std::path::Path,
+        input: &str,
+        processor: &mut TransactionProcessor,
+    ) -> Result<(Journal, Option<csv::Position>), JournalError> {
+        let mut file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(filepath)?;
+
+        let mut resume = None;
+        let mut committed_length = 0;
+        let mut reader = std::io::BufReader::new(&mut file);
+        let mut line = String::new();
+        let mut line_number = 0;
+        loop {
+            line.clear();
+            let length = reader.read_line(&mut line)?;
+            // A line without the line break is a write torn by a crash, nothing after it was committed
+            if length == 0 || !line.ends_with('\n') {
+                break;
+            }
+            line_number += 1;
+
+            let entry = serde_json::from_str::<JournalEntry>(&line)
+                .map_err(|_| JournalError::CorruptedEntry { line: line_number })?;
+            match entry {
+                JournalEntry::Start {
+                    input: journal_input,
+                } => {
+                    if journal_input != input {
+                        return Err(JournalError::InputMismatch {
+                            journal_input,
+                            input: input.to_owned(),
+                        });
+                    }
+                }
+                JournalEntry::Applied {
+                    sequence,
+                    resume: position,
+                    transaction,
+                } => {
+                    if sequence > processor.applied_transactions() {
+                        let expected = processor.applied_transactions() + 1;
+                        if sequence != expected {
+                            return Err(JournalError::OutOfSequence {
+                                expected,
+                                found: sequence,
+                            });
+                        }
+                        processor
+                            .process(&transaction)
+                            .map_err(|error| JournalError::ReplayFailed { sequence, error })?;
+                    }
+                    resume = Some(position.into());
+                }
+                JournalEntry::Checkpoint { resume: position } => resume = Some(position.into()),
+            }
+            committed_length += length as u64;
+        }
+
+        file.set_len(committed_length)?;
+        file.seek(std::io::SeekFrom::End(0))?;
+        let mut journal = Journal {
+            output: std::io::BufWriter::new(file),
+            batch_size: DEFAULT_BATCH_SIZE,
+            uncommitted: 0,
+            skipped_up_to: None,
+        };
+        if committed_length == 0 {
+            journal.write(&JournalEntry::Start {
+                input: input.to_owned(),
+            })?;
+            journal.commit()?;
+        }
+
+        Ok((journal, resume))
+    }
+
+    pub fn set_batch_size(&mut self, batch_size: usize) {
+        self.batch_size = batch_size.max(1);
+    }
+
+    pub fn record_applied(
+        &mut self,
+        sequence: u64,
+        transaction: &Transaction,
+        resume: &csv::Position,
+    ) -> Result<(), JournalError> {
+        self.skipped_up_to = None;
+        self.write(&JournalEntry::Applied {
+            sequence,
+            resume: resume.into(),
+            transaction: transaction.clone(),
+        })?;
+        self.commit_if_batch_is_full()
+    }
+
+    pub fn record_skipped(&mut self, resume: &csv::Position) -> Result<(), JournalError> {
+        self.skipped_up_to = Some(resume.clone());
+        self.uncommitted += 1;
+        self.commit_if_batch_is_full()
+    }
+
+    pub fn commit(&mut self) -> Result<(), JournalError> {
+        if let Some(position) = self.skipped_up_to.take() {
+            self.write(&JournalEntry::Checkpoint {
+                resume: (&position).into(),
+            })?;
+        }
+        self.output.flush()?;
+        self.output.get_ref().sync_data()?;
+        self.uncommitted = 0;
+        Ok(())
+    }
+
+    // Once the processor state including all the journaled transactions is safely stored,
+    // the journal is no longer needed
+    pub fn clear(&mut self) -> Result<(), JournalError> {
+        self.output.flush()?;
+        self.skipped_up_to = None;
+        self.uncommitted = 0;
+        let file = self.output.get_mut();
+        file.set_len(0)?;
+        file.seek(std::io::SeekFrom::Start(0))?;
+        file.sync_data()?;
+        Ok(())
+    }
+
+    fn commit_if_batch_is_full(&mut self) -> Result<(), JournalError> {
+        if self.uncommitted >= self.batch_size {
+            self.commit()?;
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, entry: &JournalEntry) -> Result<(), JournalError> {
+        serde_json::to_writer(&mut self.output, entry)?;
+        self.output.write_all(b"\n")?;
+        self.uncommitted += 1;
+        Ok(())
+    }
+}
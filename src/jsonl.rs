@@ -0,0 +1,127 @@
+use std::convert::TryFrom;
+use std::io::BufRead;
+
+use log::error;
+
+use crate::{InputFormatError, InputRecord, RawTransaction, RecordReader, Transaction};
+
+// Reads one JSON object per line, e.g. {"type": "deposit", "client": 1, "tx": 1, "amount": "2.5"}.
+// Positions follow the CSV conventions, lines are 1-based and there is no header record.
+pub struct JsonLinesReader<JsonInput: std::io::Read> {
+    input: std::io::BufReader<JsonInput>,
+    position: csv::Position,
+    line: Vec<u8>,
+}
+
+impl JsonLinesReader<std::fs::File> {
+    pub fn from_path(filepath: &std::path::Path) -> Result<Self, std::io::Error> {
+        Ok(JsonLinesReader::from_reader(std::fs::File::open(filepath)?))
+    }
+}
+
+impl<JsonInput: std::io::Read> JsonLinesReader<JsonInput> {
+    pub fn from_reader(input: JsonInput) -> Self {
+        let mut position = csv::Position::new();
+        position.set_line(1);
+        Self {
+            input: std::io::BufReader::new(input),
+            position,
+            line: Vec::new(),
+        }
+    }
+
+    pub fn records(&mut self) -> impl Iterator<Item = InputRecord> + '_ {
+        std::iter::from_fn(move || self.next_record())
+    }
+
+    pub fn positioned(
+        &mut self,
+    ) -> impl Iterator<Item = (csv::Position, Result<Transaction, InputFormatError>)> + '_ {
+        self.records()
+            .map(|record| (record.position, record.transaction))
+    }
+}
+
+impl<JsonInput: std::io::Read> RecordReader for JsonLinesReader<JsonInput> {
+    fn next_record(&mut self) -> Option<InputRecord> {
+        loop {
+            self.line.clear();
+            let record_position = self.position.clone();
+            let length = match self.input.read_until(b'\n', &mut self.line) {
+                Ok(0) => return None,
+                Ok(length) => length,
+                Err(err) => {
+                    return Some(InputRecord {
+                        position: record_position,
+                        row: String::new(),
+                        transaction: Err(serde_json::Error::io(err).into()),
+                    })
+                }
+            };
+            let (byte, line) = (self.position.byte(), self.position.line());
+            self.position
+                .set_byte(byte + length as u64)
+                .set_line(line + 1);
+
+            // The line is decoded only once it's consumed, so an invalid one doesn't shift the
+            // positions of the following lines
+            let decoded = std::str::from_utf8(&self.line);
+            let row = match decoded {
+                Ok(line) => line.trim().to_owned(),
+                Err(_) => String::from_utf8_lossy(&self.line).trim().to_owned(),
+            };
+            // Blank lines aren't records
+            if row.is_empty() {
+                continue;
+            }
+            let record = self.position.record();
+            self.position.set_record(record + 1);
+
+            let transaction = match decoded {
+                Ok(line) => serde_json::from_str::<RawTransaction>(line.trim())
+                    .map_err(InputFormatError::from)
+                    .and_then(Transaction::try_from),
+                Err(err) => Err(serde_json::Error::io(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    err,
+                ))
+                .into()),
+            };
+            return Some(InputRecord {
+                position: record_position,
+                row,
+                transaction,
+            });
+        }
+    }
+
+    fn current_position(&self) -> &csv::Position {
+        &self.position
+    }
+}
+
+impl<JsonInput: std::io::Read + std::io::Seek> JsonLinesReader<JsonInput> {
+    pub fn seek(&mut self, position: csv::Position) -> Result<(), std::io::Error> {
+        std::io::Seek::seek(&mut self.input, std::io::SeekFrom::Start(position.byte()))?;
+        self.position = position;
+        Ok(())
+    }
+}
+
+impl<JsonInput: std::io::Read> std::iter::Iterator for JsonLinesReader<JsonInput> {
+    type Item = Transaction;
+
+    fn next(&mut self) -> Option<Transaction> {
+        while let Some(record) = self.next_record() {
+            match record.transaction {
+                Ok(transaction) => return Some(transaction),
+                Err(err) => error!(
+                    "JSON parsing error on line {}: {:?}",
+                    record.position.line(),
+                    &err
+                ),
+            }
+        }
+        None
+    }
+}
@@ -0,0 +1,357 @@
+use std::collections::HashMap;
+
+use rust_decimal::{prelude::Zero, Decimal};
+use serde::{Deserialize, Serialize};
+
+use crate::{Account, ClientID, TransactionID};
+
+// Client funds are liabilities of the processor and grow with credits. Cash is the asset backing
+// them, disputes clearing offsets the withdrawals credited back into held funds while they are
+// disputed, and chargeback loss is what the processor pays for such withdrawals charged back.
+// Transfers between clients pass through the transfers clearing, so both sides can be posted to the
+// ledgers of different shards. The house account receives the fees paid by the clients.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LedgerAccount {
+    Cash,
+    DisputesClearing,
+    ChargebackLoss,
+    TransfersClearing,
+    House,
+    Available(ClientID),
+    Held(ClientID),
+}
+
+impl LedgerAccount {
+    pub fn client_id(&self) -> Option<ClientID> {
+        match self {
+            LedgerAccount::Available(client_id) | LedgerAccount::Held(client_id) => {
+                Some(*client_id)
+            }
+            _ => None,
+        }
+    }
+
+    // System accounts first, then both accounts of every client together
+    fn sort_key(&self) -> (u8, u16, u8) {
+        match self {
+            LedgerAccount::Cash => (0, 0, 0),
+            LedgerAccount::DisputesClearing => (1, 0, 0),
+            LedgerAccount::ChargebackLoss => (2, 0, 0),
+            LedgerAccount::TransfersClearing => (3, 0, 0),
+            LedgerAccount::House => (4, 0, 0),
+            LedgerAccount::Available(client_id) => (5, client_id.id, 0),
+            LedgerAccount::Held(client_id) => (5, client_id.id, 1),
+        }
+    }
+}
+
+impl std::fmt::Display for LedgerAccount {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            LedgerAccount::Cash => write!(f, "cash"),
+            LedgerAccount::DisputesClearing => write!(f, "disputes clearing"),
+            LedgerAccount::ChargebackLoss => write!(f, "chargeback loss"),
+            LedgerAccount::TransfersClearing => write!(f, "transfers clearing"),
+            LedgerAccount::House => write!(f, "house"),
+            LedgerAccount::Available(client_id) => write!(f, "client {} available", client_id.id),
+            LedgerAccount::Held(client_id) => write!(f, "client {} held", client_id.id),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Posting {
+    pub debit: LedgerAccount,
+    pub credit: LedgerAccount,
+    pub amount: Decimal,
+}
+
+impl Posting {
+    pub fn new(debit: LedgerAccount, credit: LedgerAccount, amount: Decimal) -> Posting {
+        Posting {
+            debit,
+            credit,
+            amount,
+        }
+    }
+
+    // Change of the credit balance of the account
+    pub fn credit_change(&self, account: LedgerAccount) -> Decimal {
+        let mut change = Decimal::zero();
+        if self.credit == account {
+            change += self.amount;
+        }
+        if self.debit == account {
+            change -= self.amount;
+        }
+        change
+    }
+}
+
+// A posting made by a transaction
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+pub struct LedgerEntry {
+    pub transaction_id: TransactionID,
+    #[serde(flatten)]
+    pub posting: Posting,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct LedgerBalance {
+    pub debit: Decimal,
+    pub credit: Decimal,
+}
+
+impl LedgerBalance {
+    pub(crate) fn credit_balance(&self) -> Decimal {
+        self.credit - self.debit
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TrialBalanceRow {
+    // No account for the row of the totals
+    pub account: Option<LedgerAccount>,
+    // Only one side of the net balance is non-zero, apart from the totals
+    pub debit: Decimal,
+    pub credit: Decimal,
+}
+
+impl TrialBalanceRow {
+    pub(crate) fn columns(&self) -> [String; 3] {
+        [
+            match self.account {
+                Some(account) => account.to_string(),
+                None => "total".to_owned(),
+            },
+            self.debit.normalize().to_string(),
+            self.credit.normalize().to_string(),
+        ]
+    }
+}
+
+impl Serialize for TrialBalanceRow {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use serde::ser::SerializeStruct;
+
+        let [account, debit, credit] = self.columns();
+        let mut state = serializer.serialize_struct("TrialBalanceRow", 3)?;
+        state.serialize_field("account", &account)?;
+        state.serialize_field("debit", &debit)?;
+        state.serialize_field("credit", &credit)?;
+        state.end()
+    }
+}
+
+// The debit and credit totals of every account, and the recorded postings themselves. Postings are
+// kept by the client of the transaction that made them, in the order they were made, which doesn't
+// depend on how the clients are split between shards.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Ledger {
+    balances: HashMap<LedgerAccount, LedgerBalance>,
+    postings: HashMap<ClientID, Vec<LedgerEntry>>,
+}
+
+#[derive(Deserialize, Serialize)]
+struct LedgerRecord {
+    account: LedgerAccount,
+    #[serde(flatten)]
+    balance: LedgerBalance,
+}
+
+#[derive(Deserialize, Serialize)]
+struct ClientPostings {
+    client: ClientID,
+    postings: Vec<LedgerEntry>,
+}
+
+#[derive(Deserialize, Serialize)]
+struct LedgerState {
+    balances: Vec<LedgerRecord>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    postings: Vec<ClientPostings>,
+}
+
+// States saved before the postings were kept only have the balances
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum SavedLedger {
+    Balances(Vec<LedgerRecord>),
+    WithPostings(LedgerState),
+}
+
+impl Serialize for Ledger {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        LedgerState {
+            balances: self
+                .balances
+                .iter()
+                .map(|(account, balance)| LedgerRecord {
+                    account: *account,
+                    balance: *balance,
+                })
+                .collect(),
+            postings: self
+                .postings
+                .iter()
+                .map(|(client_id, postings)| ClientPostings {
+                    client: *client_id,
+                    postings: postings.clone(),
+                })
+                .collect(),
+        }
+        .serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Ledger {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Ledger, D::Error> {
+        let state = match SavedLedger::deserialize(deserializer)? {
+            SavedLedger::Balances(balances) => LedgerState {
+                balances,
+                postings: Vec::new(),
+            },
+            SavedLedger::WithPostings(state) => state,
+        };
+        Ok(Ledger {
+            balances: state
+                .balances
+                .into_iter()
+                .map(|record| (record.account, record.balance))
+                .collect(),
+            postings: state
+                .postings
+                .into_iter()
+                .map(|client_postings| (client_postings.client, client_postings.postings))
+                .collect(),
+        })
+    }
+}
+
+impl Ledger {
+    // Opening balances for accounts that were kept before the ledger, all backed by cash
+    pub(crate) fn opening<'a>(
+        accounts: impl IntoIterator<Item = (&'a ClientID, &'a Account)>,
+    ) -> Ledger {
+        let mut ledger = Ledger::default();
+        for (client_id, account) in accounts {
+            ledger.add_to_balances(Posting::new(
+                LedgerAccount::Cash,
+                LedgerAccount::Available(*client_id),
+                account.available,
+            ));
+            ledger.add_to_balances(Posting::new(
+                LedgerAccount::Cash,
+                LedgerAccount::Held(*client_id),
+                account.held,
+            ));
+        }
+        ledger
+    }
+
+    pub fn post(&mut self, posting: Posting) {
+        self.add_to_balances(posting);
+    }
+
+    // Besides posting it, the posting is kept with the postings of the client whose transaction
+    // made it
+    pub fn record(&mut self, client_id: ClientID, transaction_id: TransactionID, posting: Posting) {
+        if let Some(posting) = self.add_to_balances(posting) {
+            self.postings
+                .entry(client_id)
+                .or_default()
+                .push(LedgerEntry {
+                    transaction_id,
+                    posting,
+                });
+        }
+    }
+
+    // A negative amount is the same posting in the opposite direction, the posting actually made is
+    // returned unless it's zero
+    fn add_to_balances(&mut self, posting: Posting) -> Option<Posting> {
+        if posting.amount.is_zero() {
+            return None;
+        }
+        let posting = if posting.amount.is_sign_negative() {
+            Posting::new(posting.credit, posting.debit, -posting.amount)
+        } else {
+            posting
+        };
+        self.balances.entry(posting.debit).or_default().debit += posting.amount;
+        self.balances.entry(posting.credit).or_default().credit += posting.amount;
+        Some(posting)
+    }
+
+    // Only recorded with `ProcessorConfig::record_postings`
+    pub fn postings(&self, client_id: ClientID) -> &[LedgerEntry] {
+        self.postings.get(&client_id).map_or(&[], Vec::as_slice)
+    }
+
+    pub fn balance(&self, account: LedgerAccount) -> LedgerBalance {
+        self.balances.get(&account).copied().unwrap_or_default()
+    }
+
+    // Client accounts derived from their available and held ledger accounts
+    pub fn client_balances(&self, client_id: ClientID) -> (Decimal, Decimal) {
+        (
+            self.balance(LedgerAccount::Available(client_id))
+                .credit_balance(),
+            self.balance(LedgerAccount::Held(client_id))
+                .credit_balance(),
+        )
+    }
+
+    // Net balance of every account followed by the totals, which are equal for a balanced ledger
+    pub fn trial_balance(&self) -> Vec<TrialBalanceRow> {
+        let mut accounts = self.balances.iter().collect::<Vec<_>>();
+        accounts.sort_unstable_by_key(|(account, _)| account.sort_key());
+        let mut total = TrialBalanceRow {
+            account: None,
+            debit: Decimal::zero(),
+            credit: Decimal::zero(),
+        };
+        let mut rows = Vec::with_capacity(accounts.len() + 1);
+        for (account, balance) in accounts {
+            let net = balance.debit - balance.credit;
+            let row = TrialBalanceRow {
+                account: Some(*account),
+                debit: net.max(Decimal::zero()),
+                credit: (-net).max(Decimal::zero()),
+            };
+            total.debit += row.debit;
+            total.credit += row.credit;
+            rows.push(row);
+        }
+        rows.push(total);
+        rows
+    }
+
+    pub(crate) fn split(
+        self,
+        shard_of_client: impl Fn(ClientID) -> usize,
+        shards: usize,
+    ) -> Vec<Ledger> {
+        let mut ledgers = vec![Ledger::default(); shards];
+        for (account, balance) in self.balances {
+            let shard = account.client_id().map_or(0, &shard_of_client);
+            ledgers[shard].balances.insert(account, balance);
+        }
+        for (client_id, postings) in self.postings {
+            ledgers[shard_of_client(client_id)]
+                .postings
+                .insert(client_id, postings);
+        }
+        ledgers
+    }
+
+    pub(crate) fn merge(&mut self, other: Ledger) {
+        for (account, balance) in other.balances {
+            let merged = self.balances.entry(account).or_default();
+            merged.debit += balance.debit;
+            merged.credit += balance.credit;
+        }
+        self.postings.extend(other.postings);
+    }
+}
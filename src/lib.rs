@@ -1,6 +1,41 @@
 use log::{error, warn};
 use std::convert::TryFrom;
 
+mod audit;
+mod client_transfer;
+mod compact_store;
+mod expiry;
+mod fees;
+mod file_store;
+mod history;
+pub mod http;
+mod id_set;
+mod journal;
+mod jsonl;
+mod ledger;
+mod observer;
+mod report;
+pub mod server;
+mod sharded;
+mod state;
+mod store;
+pub use audit::Discrepancy;
+pub use compact_store::CompactTransferStore;
+pub use fees::{FeeRate, FeeSchedule};
+pub use file_store::FileTransferStore;
+use history::StatementRow;
+pub use history::{HistoryEntry, HistoryEventType};
+pub use journal::{Journal, JournalError};
+pub use jsonl::JsonLinesReader;
+pub use ledger::{Ledger, LedgerAccount, LedgerBalance, LedgerEntry, Posting, TrialBalanceRow};
+pub use observer::{AccountEvent, AccountEventType, Observer};
+pub use report::{
+    write_accounts, write_statement, write_trial_balance, AccountOrder, AccountWriter, OutputFormat,
+};
+pub use sharded::{Rejected, ShardedProcessor};
+pub use state::StateError;
+pub use store::{MemoryTransferStore, TransferStore};
+
 use rust_decimal::{prelude::Zero, Decimal};
 use serde::{Deserialize, Serialize};
 
@@ -8,7 +43,11 @@ use serde::{Deserialize, Serialize};
 pub enum InputFormatError {
     MissingAmount,
     NegativeAmount,
+    MissingReason,
+    MissingDestination,
+    TransferToTheSameClient,
     CsvError(csv::Error),
+    JsonError(serde_json::Error),
 }
 
 impl std::convert::From<csv::Error> for InputFormatError {
@@ -17,6 +56,12 @@ impl std::convert::From<csv::Error> for InputFormatError {
     }
 }
 
+impl std::convert::From<serde_json::Error> for InputFormatError {
+    fn from(json_error: serde_json::Error) -> InputFormatError {
+        InputFormatError::JsonError(json_error)
+    }
+}
+
 impl std::fmt::Display for InputFormatError {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         write!(f, "{:?}", self)
@@ -25,6 +70,32 @@ impl std::fmt::Display for InputFormatError {
 
 impl std::error::Error for InputFormatError {}
 
+impl InputFormatError {
+    pub fn name(&self) -> &'static str {
+        match self {
+            InputFormatError::MissingAmount => "MissingAmount",
+            InputFormatError::NegativeAmount => "NegativeAmount",
+            InputFormatError::MissingReason => "MissingReason",
+            InputFormatError::MissingDestination => "MissingDestination",
+            InputFormatError::TransferToTheSameClient => "TransferToTheSameClient",
+            InputFormatError::CsvError(_) => "CsvError",
+            InputFormatError::JsonError(_) => "JsonError",
+        }
+    }
+
+    pub fn code(&self) -> &'static str {
+        match self {
+            InputFormatError::MissingAmount => "E101",
+            InputFormatError::NegativeAmount => "E102",
+            InputFormatError::CsvError(_) => "E103",
+            InputFormatError::JsonError(_) => "E104",
+            InputFormatError::MissingReason => "E105",
+            InputFormatError::MissingDestination => "E106",
+            InputFormatError::TransferToTheSameClient => "E107",
+        }
+    }
+}
+
 #[derive(Debug)]
 pub enum ProcessingError {
     TransferOnLockedAccount,
@@ -36,8 +107,122 @@ pub enum ProcessingError {
     ChargedBackTransferWasNotInDispute,
     DisputingAlreadyChargedBackTransfer,
     TransactionIdAlreadyExists,
+    UnlockingAccountThatIsNotLocked,
+    DisputedAmountExceedsTransfer,
+    DisputingWithdrawalIsNotAllowed,
+    DisputeWindowExpired,
+    TimestampOutOfOrder,
+    TransferToLockedAccount,
+    DisputingClientTransferIsNotAllowed,
+    FeeForUnknownClient,
+    NotEnoughMoneyForFee,
+    DisputingFeeIsNotAllowed,
+}
+
+impl ProcessingError {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ProcessingError::TransferOnLockedAccount => "TransferOnLockedAccount",
+            ProcessingError::NotEnoughMoneyForWithdrawal => "NotEnoughMoneyForWithdrawal",
+            ProcessingError::TryingToDisputeUnknownTransaction => {
+                "TryingToDisputeUnknownTransaction"
+            }
+            ProcessingError::WrongClientInDispute => "WrongClientInDispute",
+            ProcessingError::TransferIsAlreadyInDispute => "TransferIsAlreadyInDispute",
+            ProcessingError::ResolvedTransferWasNotInDispute => "ResolvedTransferWasNotInDispute",
+            ProcessingError::ChargedBackTransferWasNotInDispute => {
+                "ChargedBackTransferWasNotInDispute"
+            }
+            ProcessingError::DisputingAlreadyChargedBackTransfer => {
+                "DisputingAlreadyChargedBackTransfer"
+            }
+            ProcessingError::TransactionIdAlreadyExists => "TransactionIdAlreadyExists",
+            ProcessingError::UnlockingAccountThatIsNotLocked => "UnlockingAccountThatIsNotLocked",
+            ProcessingError::DisputedAmountExceedsTransfer => "DisputedAmountExceedsTransfer",
+            ProcessingError::DisputingWithdrawalIsNotAllowed => "DisputingWithdrawalIsNotAllowed",
+            ProcessingError::DisputeWindowExpired => "DisputeWindowExpired",
+            ProcessingError::TimestampOutOfOrder => "TimestampOutOfOrder",
+            ProcessingError::TransferToLockedAccount => "TransferToLockedAccount",
+            ProcessingError::DisputingClientTransferIsNotAllowed => {
+                "DisputingClientTransferIsNotAllowed"
+            }
+            ProcessingError::FeeForUnknownClient => "FeeForUnknownClient",
+            ProcessingError::NotEnoughMoneyForFee => "NotEnoughMoneyForFee",
+            ProcessingError::DisputingFeeIsNotAllowed => "DisputingFeeIsNotAllowed",
+        }
+    }
+
+    pub fn code(&self) -> &'static str {
+        match self {
+            ProcessingError::TransferOnLockedAccount => "E201",
+            ProcessingError::NotEnoughMoneyForWithdrawal => "E202",
+            ProcessingError::TryingToDisputeUnknownTransaction => "E203",
+            ProcessingError::WrongClientInDispute => "E204",
+            ProcessingError::TransferIsAlreadyInDispute => "E205",
+            ProcessingError::ResolvedTransferWasNotInDispute => "E206",
+            ProcessingError::ChargedBackTransferWasNotInDispute => "E207",
+            ProcessingError::DisputingAlreadyChargedBackTransfer => "E208",
+            ProcessingError::TransactionIdAlreadyExists => "E209",
+            ProcessingError::UnlockingAccountThatIsNotLocked => "E210",
+            ProcessingError::DisputedAmountExceedsTransfer => "E211",
+            ProcessingError::DisputingWithdrawalIsNotAllowed => "E212",
+            ProcessingError::DisputeWindowExpired => "E213",
+            ProcessingError::TimestampOutOfOrder => "E214",
+            ProcessingError::TransferToLockedAccount => "E215",
+            ProcessingError::DisputingClientTransferIsNotAllowed => "E216",
+            ProcessingError::FeeForUnknownClient => "E217",
+            ProcessingError::NotEnoughMoneyForFee => "E218",
+            ProcessingError::DisputingFeeIsNotAllowed => "E219",
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum RecordError {
+    InputFormatError(InputFormatError),
+    ProcessingError(ProcessingError),
+}
+
+impl RecordError {
+    pub fn name(&self) -> &'static str {
+        match self {
+            RecordError::InputFormatError(err) => err.name(),
+            RecordError::ProcessingError(err) => err.name(),
+        }
+    }
+
+    pub fn code(&self) -> &'static str {
+        match self {
+            RecordError::InputFormatError(err) => err.code(),
+            RecordError::ProcessingError(err) => err.code(),
+        }
+    }
 }
 
+// The first record that failed in strict mode
+#[derive(Debug)]
+pub struct StrictModeError {
+    pub position: csv::Position,
+    pub row: String,
+    pub error: RecordError,
+}
+
+impl std::fmt::Display for StrictModeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "line {} (byte {}, record {}) failed with {} {}",
+            self.position.line(),
+            self.position.byte(),
+            self.position.record(),
+            self.error.code(),
+            self.error.name()
+        )
+    }
+}
+
+impl std::error::Error for StrictModeError {}
+
 #[derive(Debug, Clone, Copy, Deserialize, Serialize, PartialEq, Eq, Hash)]
 #[serde(transparent)]
 pub struct TransactionID {
@@ -50,7 +235,7 @@ impl TransactionID {
     }
 }
 
-#[derive(Debug, Clone, Copy, Deserialize, Serialize, PartialEq, Eq, Hash)]
+#[derive(Debug, Clone, Copy, Deserialize, Serialize, PartialEq, Eq, PartialOrd, Ord, Hash)]
 #[serde(transparent)]
 pub struct ClientID {
     id: u16,
@@ -62,14 +247,71 @@ impl ClientID {
     }
 }
 
-#[derive(Debug, Clone, Copy, Deserialize, PartialEq, Eq)]
+// Milliseconds since the Unix epoch. Parsed from either the milliseconds or an RFC 3339 date and time,
+// always serialized as the milliseconds.
+#[derive(Debug, Clone, Copy, Serialize, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[serde(transparent)]
+pub struct Timestamp {
+    millis: u64,
+}
+
+impl Timestamp {
+    pub fn from_millis(millis: u64) -> Timestamp {
+        Timestamp { millis }
+    }
+
+    pub fn millis(&self) -> u64 {
+        self.millis
+    }
+}
+
+impl std::str::FromStr for Timestamp {
+    type Err = String;
+
+    fn from_str(timestamp: &str) -> Result<Timestamp, String> {
+        if let Ok(millis) = timestamp.parse() {
+            return Ok(Timestamp { millis });
+        }
+        let date_time = chrono::DateTime::parse_from_rfc3339(timestamp)
+            .map_err(|err| format!("Invalid timestamp {}: {}", timestamp, err))?;
+        u64::try_from(date_time.timestamp_millis())
+            .map(Timestamp::from_millis)
+            .map_err(|_| format!("Timestamp {} is before the Unix epoch", timestamp))
+    }
+}
+
+impl<'de> Deserialize<'de> for Timestamp {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Timestamp, D::Error> {
+        struct TimestampVisitor;
+
+        impl<'de> serde::de::Visitor<'de> for TimestampVisitor {
+            type Value = Timestamp;
+
+            fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                f.write_str("milliseconds since the Unix epoch or an RFC 3339 date and time")
+            }
+
+            fn visit_u64<E: serde::de::Error>(self, millis: u64) -> Result<Timestamp, E> {
+                Ok(Timestamp { millis })
+            }
+
+            fn visit_str<E: serde::de::Error>(self, timestamp: &str) -> Result<Timestamp, E> {
+                timestamp.parse().map_err(E::custom)
+            }
+        }
+
+        deserializer.deserialize_any(TimestampVisitor)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Deserialize, Serialize, PartialEq, Eq)]
 #[serde(rename_all = "lowercase")]
 pub enum TransferType {
     Deposit,
     Withdrawal,
 }
 
-#[derive(Debug, Clone, Deserialize, PartialEq, Eq)]
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq, Eq)]
 pub struct Transfer {
     #[serde(alias = "type")]
     pub transfer_type: TransferType,
@@ -78,9 +320,11 @@ pub struct Transfer {
     #[serde(alias = "tx")]
     pub transaction_id: TransactionID,
     pub amount: Decimal,
+    #[serde(default)]
+    pub timestamp: Option<Timestamp>,
 }
 
-#[derive(Debug, Clone, Copy, Deserialize, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, Deserialize, Serialize, PartialEq, Eq)]
 #[serde(rename_all = "lowercase")]
 pub enum AmendmentType {
     Dispute,
@@ -88,7 +332,7 @@ pub enum AmendmentType {
     Chargeback,
 }
 
-#[derive(Debug, Clone, Deserialize, PartialEq, Eq)]
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq, Eq)]
 pub struct Amendment {
     #[serde(alias = "type")]
     pub amendment_type: AmendmentType,
@@ -96,13 +340,84 @@ pub struct Amendment {
     pub client_id: ClientID,
     #[serde(alias = "tx")]
     pub transaction_id: TransactionID,
+    // Only set for partial disputes, otherwise the entire transfer is disputed
+    #[serde(default)]
+    pub amount: Option<Decimal>,
+    #[serde(default)]
+    pub timestamp: Option<Timestamp>,
 }
 
-#[derive(Debug, Deserialize, PartialEq, Eq)]
+// Administrative actions are taken by the support staff rather than by the clients
+#[derive(Debug, Clone, Copy, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum AdminActionType {
+    #[serde(alias = "reinstate")]
+    Unlock,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq, Eq)]
+pub struct AdminAction {
+    #[serde(alias = "type")]
+    pub action_type: AdminActionType,
+    #[serde(alias = "client")]
+    pub client_id: ClientID,
+    #[serde(alias = "tx")]
+    pub transaction_id: TransactionID,
+    pub reason: String,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ClientTransferType {
+    Transfer,
+}
+
+// Moves money from the available funds of one client to another's. Client transfers can't be
+// disputed, a mistaken one is reverted by a transfer back.
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq, Eq)]
+pub struct ClientTransfer {
+    #[serde(alias = "type")]
+    pub transfer_type: ClientTransferType,
+    // The source client
+    #[serde(alias = "client")]
+    pub client_id: ClientID,
+    #[serde(alias = "destination")]
+    pub destination_client_id: ClientID,
+    #[serde(alias = "tx")]
+    pub transaction_id: TransactionID,
+    pub amount: Decimal,
+    #[serde(default)]
+    pub timestamp: Option<Timestamp>,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum FeeType {
+    Fee,
+}
+
+// Charged by the house on top of the fees of the `FeeSchedule`, even on a locked account
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq, Eq)]
+pub struct Fee {
+    #[serde(alias = "type")]
+    pub fee_type: FeeType,
+    #[serde(alias = "client")]
+    pub client_id: ClientID,
+    #[serde(alias = "tx")]
+    pub transaction_id: TransactionID,
+    pub amount: Decimal,
+    #[serde(default)]
+    pub timestamp: Option<Timestamp>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq, Eq)]
 #[serde(untagged)]
 pub enum Transaction {
     Transfer(Transfer),
     Amendment(Amendment),
+    AdminAction(AdminAction),
+    ClientTransfer(ClientTransfer),
+    Fee(Fee),
 }
 
 impl Transaction {
@@ -110,6 +425,29 @@ impl Transaction {
         match self {
             Transaction::Transfer(transfer) => transfer.transaction_id,
             Transaction::Amendment(amendment) => amendment.transaction_id,
+            Transaction::AdminAction(admin_action) => admin_action.transaction_id,
+            Transaction::ClientTransfer(client_transfer) => client_transfer.transaction_id,
+            Transaction::Fee(fee) => fee.transaction_id,
+        }
+    }
+
+    pub fn client_id(&self) -> ClientID {
+        match self {
+            Transaction::Transfer(transfer) => transfer.client_id,
+            Transaction::Amendment(amendment) => amendment.client_id,
+            Transaction::AdminAction(admin_action) => admin_action.client_id,
+            Transaction::ClientTransfer(client_transfer) => client_transfer.client_id,
+            Transaction::Fee(fee) => fee.client_id,
+        }
+    }
+
+    pub fn timestamp(&self) -> Option<Timestamp> {
+        match self {
+            Transaction::Transfer(transfer) => transfer.timestamp,
+            Transaction::Amendment(amendment) => amendment.timestamp,
+            Transaction::AdminAction(_) => None,
+            Transaction::ClientTransfer(client_transfer) => client_transfer.timestamp,
+            Transaction::Fee(fee) => fee.timestamp,
         }
     }
 }
@@ -130,6 +468,44 @@ impl std::fmt::Display for Amendment {
             f,
             "{:?}, client_id : {}, transaction_id : {}",
             self.amendment_type, self.client_id.id, self.transaction_id.id
+        )?;
+        match self.amount {
+            Some(amount) => write!(f, ", amount : {}", amount),
+            None => Ok(()),
+        }
+    }
+}
+
+impl std::fmt::Display for AdminAction {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "{:?}, client_id : {}, transaction_id : {}, reason : {}",
+            self.action_type, self.client_id.id, self.transaction_id.id, self.reason
+        )
+    }
+}
+
+impl std::fmt::Display for ClientTransfer {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "{:?}, client_id : {}, destination_client_id : {}, transaction_id : {}, amount : {}",
+            self.transfer_type,
+            self.client_id.id,
+            self.destination_client_id.id,
+            self.transaction_id.id,
+            self.amount
+        )
+    }
+}
+
+impl std::fmt::Display for Fee {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "{:?}, client_id : {}, transaction_id : {}, amount : {}",
+            self.fee_type, self.client_id.id, self.transaction_id.id, self.amount
         )
     }
 }
@@ -139,6 +515,9 @@ impl std::fmt::Display for Transaction {
         match self {
             Transaction::Transfer(transfer) => transfer.fmt(f),
             Transaction::Amendment(amendment) => amendment.fmt(f),
+            Transaction::AdminAction(admin_action) => admin_action.fmt(f),
+            Transaction::ClientTransfer(client_transfer) => client_transfer.fmt(f),
+            Transaction::Fee(fee) => fee.fmt(f),
         }
     }
 }
@@ -148,6 +527,9 @@ impl std::fmt::Display for Transaction {
 pub enum TransactionType {
     Transfer(TransferType),
     Amendment(AmendmentType),
+    AdminAction(AdminActionType),
+    ClientTransfer(ClientTransferType),
+    Fee(FeeType),
 }
 
 #[derive(Debug, Deserialize)]
@@ -159,6 +541,9 @@ struct RawTransaction {
     #[serde(alias = "tx")]
     transaction_id: TransactionID,
     amount: Option<Decimal>,
+    reason: Option<String>,
+    timestamp: Option<Timestamp>,
+    destination: Option<ClientID>,
 }
 
 impl std::convert::TryFrom<RawTransaction> for Transaction {
@@ -166,16 +551,26 @@ impl std::convert::TryFrom<RawTransaction> for Transaction {
     fn try_from(transaction: RawTransaction) -> Result<Transaction, Self::Error> {
         match transaction.transaction_type {
             TransactionType::Amendment(amendment_type) => {
-                if transaction.amount.is_some() {
-                    warn!(
-                        "Amount on transation {:?} will be ignored. You can only dispute the entire transfer and can't alter the amount being disputed",
-                        &transaction
-                    );
-                }
+                let amount = match (amendment_type, transaction.amount) {
+                    (_, Some(amount)) if amount < Decimal::zero() => {
+                        return Err(InputFormatError::NegativeAmount)
+                    }
+                    (AmendmentType::Dispute, amount) => amount,
+                    (_, Some(_)) => {
+                        warn!(
+                            "Amount on transation {:?} will be ignored. Resolve and chargeback always settle the entire disputed amount",
+                            &transaction
+                        );
+                        None
+                    }
+                    (_, None) => None,
+                };
                 Ok(Transaction::Amendment(Amendment {
                     amendment_type,
                     client_id: transaction.client_id,
                     transaction_id: transaction.transaction_id,
+                    amount,
+                    timestamp: transaction.timestamp,
                 }))
             }
             TransactionType::Transfer(transfer_type) => match transaction.amount {
@@ -188,17 +583,140 @@ impl std::convert::TryFrom<RawTransaction> for Transaction {
                             amount,
                             client_id: transaction.client_id,
                             transaction_id: transaction.transaction_id,
+                            timestamp: transaction.timestamp,
                         }))
                     }
                 }
                 None => Err(InputFormatError::MissingAmount),
             },
+            TransactionType::AdminAction(action_type) => {
+                if transaction.amount.is_some() {
+                    warn!("Amount on transation {:?} will be ignored", &transaction);
+                }
+                match transaction.reason {
+                    Some(reason) => Ok(Transaction::AdminAction(AdminAction {
+                        action_type,
+                        client_id: transaction.client_id,
+                        transaction_id: transaction.transaction_id,
+                        reason,
+                    })),
+                    None => Err(InputFormatError::MissingReason),
+                }
+            }
+            TransactionType::ClientTransfer(transfer_type) => {
+                let amount = match transaction.amount {
+                    Some(amount) if amount < Decimal::zero() => {
+                        return Err(InputFormatError::NegativeAmount)
+                    }
+                    Some(amount) => amount,
+                    None => return Err(InputFormatError::MissingAmount),
+                };
+                match transaction.destination {
+                    Some(destination) if destination == transaction.client_id => {
+                        Err(InputFormatError::TransferToTheSameClient)
+                    }
+                    Some(destination) => Ok(Transaction::ClientTransfer(ClientTransfer {
+                        transfer_type,
+                        client_id: transaction.client_id,
+                        destination_client_id: destination,
+                        transaction_id: transaction.transaction_id,
+                        amount,
+                        timestamp: transaction.timestamp,
+                    })),
+                    None => Err(InputFormatError::MissingDestination),
+                }
+            }
+            TransactionType::Fee(fee_type) => match transaction.amount {
+                Some(amount) if amount < Decimal::zero() => Err(InputFormatError::NegativeAmount),
+                Some(amount) => Ok(Transaction::Fee(Fee {
+                    fee_type,
+                    client_id: transaction.client_id,
+                    transaction_id: transaction.transaction_id,
+                    amount,
+                    timestamp: transaction.timestamp,
+                })),
+                None => Err(InputFormatError::MissingAmount),
+            },
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct InputRecord {
+    pub position: csv::Position,
+    pub row: String,
+    pub transaction: Result<Transaction, InputFormatError>,
+}
+
+pub trait RecordReader {
+    fn next_record(&mut self) -> Option<InputRecord>;
+
+    // Position right after the last returned record
+    fn current_position(&self) -> &csv::Position;
+}
+
+const CSV_COLUMNS: [&str; 7] = [
+    "type",
+    "client",
+    "tx",
+    "amount",
+    "reason",
+    "timestamp",
+    "destination",
+];
+
+// Keeps the input read since the start of the current record, so the rows are reported exactly as
+// they were in the input, quoting included
+struct RecordingReader<Input> {
+    input: Input,
+    recorded: Vec<u8>,
+    // Offset of the first recorded byte in the input
+    recorded_from: u64,
+}
+
+impl<Input> RecordingReader<Input> {
+    fn new(input: Input) -> Self {
+        Self {
+            input,
+            recorded: Vec::new(),
+            recorded_from: 0,
         }
     }
+
+    // Row between the given offsets of the input, forgetting everything up to its end
+    fn take_row(&mut self, start: u64, end: u64) -> String {
+        let start = (start.saturating_sub(self.recorded_from) as usize).min(self.recorded.len());
+        let end =
+            (end.saturating_sub(self.recorded_from) as usize).clamp(start, self.recorded.len());
+        let row = String::from_utf8_lossy(&self.recorded[start..end])
+            .trim()
+            .to_owned();
+        self.recorded.drain(..end);
+        self.recorded_from += end as u64;
+        row
+    }
+}
+
+impl<Input: std::io::Read> std::io::Read for RecordingReader<Input> {
+    fn read(&mut self, buffer: &mut [u8]) -> std::io::Result<usize> {
+        let read = self.input.read(buffer)?;
+        self.recorded.extend_from_slice(&buffer[..read]);
+        Ok(read)
+    }
+}
+
+impl<Input: std::io::Seek> std::io::Seek for RecordingReader<Input> {
+    fn seek(&mut self, position: std::io::SeekFrom) -> std::io::Result<u64> {
+        let offset = self.input.seek(position)?;
+        self.recorded.clear();
+        self.recorded_from = offset;
+        Ok(offset)
+    }
 }
 
 pub struct CsvReader<CsvInput: std::io::Read> {
-    csv_reader: csv::Reader<CsvInput>,
+    csv_reader: csv::Reader<RecordingReader<CsvInput>>,
+    headers: Option<Option<csv::StringRecord>>,
 }
 
 impl CsvReader<std::fs::File> {
@@ -213,19 +731,89 @@ impl<CsvInput: std::io::Read> CsvReader<CsvInput> {
             csv_reader: csv::ReaderBuilder::new()
                 .trim(csv::Trim::All)
                 .flexible(true)
-                .from_reader(input),
+                .from_reader(RecordingReader::new(input)),
+            headers: None,
         }
     }
 
-    fn get_next_transaction(&mut self) -> Option<Result<Transaction, InputFormatError>> {
-        self.csv_reader
-            .deserialize::<RawTransaction>()
-            .next()
-            .map(|result| {
-                result
-                    .map_err(InputFormatError::from)
-                    .and_then(Transaction::try_from)
-            })
+    // For rows that come without the header line, e.g. one by one over the network
+    pub fn without_headers(input: CsvInput) -> Self {
+        Self {
+            csv_reader: csv::ReaderBuilder::new()
+                .trim(csv::Trim::All)
+                .flexible(true)
+                .has_headers(false)
+                .from_reader(RecordingReader::new(input)),
+            headers: Some(Some(csv::StringRecord::from(CSV_COLUMNS.to_vec()))),
+        }
+    }
+
+    pub fn records(&mut self) -> impl Iterator<Item = InputRecord> + '_ {
+        std::iter::from_fn(move || self.next_record())
+    }
+
+    pub fn positioned(
+        &mut self,
+    ) -> impl Iterator<Item = (csv::Position, Result<Transaction, InputFormatError>)> + '_ {
+        self.records()
+            .map(|record| (record.position, record.transaction))
+    }
+}
+
+impl<CsvInput: std::io::Read> RecordReader for CsvReader<CsvInput> {
+    fn current_position(&self) -> &csv::Position {
+        self.csv_reader.position()
+    }
+
+    fn next_record(&mut self) -> Option<InputRecord> {
+        if self.headers.is_none() {
+            self.headers = Some(self.csv_reader.headers().ok().cloned());
+        }
+
+        let mut record = csv::StringRecord::new();
+        loop {
+            let read = self.csv_reader.read_record(&mut record);
+            let position = match &read {
+                Ok(_) => record.position(),
+                Err(err) => err.position(),
+            }
+            .cloned();
+            let end = self.csv_reader.position().byte();
+            let row = match &position {
+                Some(position) => self.csv_reader.get_mut().take_row(position.byte(), end),
+                None => String::new(),
+            };
+            let position = position.unwrap_or_else(csv::Position::new);
+            return match read {
+                Ok(false) => None,
+                // Whitespace-only lines aren't rows
+                Ok(true) if record.iter().all(str::is_empty) => continue,
+                Ok(true) => Some(InputRecord {
+                    position,
+                    row,
+                    transaction: record
+                        .deserialize::<RawTransaction>(
+                            self.headers.as_ref().and_then(Option::as_ref),
+                        )
+                        .map_err(InputFormatError::from)
+                        .and_then(Transaction::try_from),
+                }),
+                Err(err) => Some(InputRecord {
+                    position,
+                    row,
+                    transaction: Err(err.into()),
+                }),
+            };
+        }
+    }
+}
+
+impl<CsvInput: std::io::Read + std::io::Seek> CsvReader<CsvInput> {
+    pub fn seek(&mut self, position: csv::Position) -> Result<(), csv::Error> {
+        if self.headers.is_none() {
+            self.headers = Some(self.csv_reader.headers().ok().cloned());
+        }
+        self.csv_reader.seek(position)
     }
 }
 
@@ -233,17 +821,109 @@ impl<CsvInput: std::io::Read> std::iter::Iterator for CsvReader<CsvInput> {
     type Item = Transaction;
 
     fn next(&mut self) -> Option<Transaction> {
-        while let Some(result) = self.get_next_transaction() {
-            match result {
+        while let Some(record) = self.next_record() {
+            match record.transaction {
                 Ok(transaction) => return Some(transaction),
-                Err(err) => error!("CSV parsing error: {:?}", &err),
+                Err(err) => error!(
+                    "CSV parsing error on line {}: {:?}",
+                    record.position.line(),
+                    &err
+                ),
             }
         }
         None
     }
 }
 
-#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[derive(Debug, Serialize)]
+struct RejectRecord<'a> {
+    line: u64,
+    byte: u64,
+    record: u64,
+    code: &'a str,
+    error: &'a str,
+    row: &'a str,
+}
+
+pub struct RejectWriter<Output: std::io::Write> {
+    csv_writer: csv::Writer<Output>,
+}
+
+impl RejectWriter<std::fs::File> {
+    pub fn from_path(filepath: &std::path::Path) -> Result<Self, std::io::Error> {
+        Ok(RejectWriter::from_writer(std::fs::File::create(filepath)?))
+    }
+
+    // Keeps the rejects already in the file, e.g. the ones of an interrupted run being resumed, and
+    // only writes the header into an empty file
+    pub fn appending_to_path(filepath: &std::path::Path) -> Result<Self, std::io::Error> {
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(filepath)?;
+        let is_empty = file.metadata()?.len() == 0;
+        Ok(Self {
+            csv_writer: csv::WriterBuilder::new()
+                .has_headers(is_empty)
+                .from_writer(file),
+        })
+    }
+}
+
+impl<Output: std::io::Write> RejectWriter<Output> {
+    pub fn from_writer(output: Output) -> Self {
+        Self {
+            csv_writer: csv::Writer::from_writer(output),
+        }
+    }
+
+    pub fn write_input_error(
+        &mut self,
+        position: &csv::Position,
+        row: &str,
+        err: &InputFormatError,
+    ) -> Result<(), csv::Error> {
+        self.write(position, row, err.name(), err.code())
+    }
+
+    pub fn write_processing_error(
+        &mut self,
+        position: &csv::Position,
+        row: &str,
+        err: &ProcessingError,
+    ) -> Result<(), csv::Error> {
+        self.write(position, row, err.name(), err.code())
+    }
+
+    pub fn flush(&mut self) -> Result<(), std::io::Error> {
+        self.csv_writer.flush()
+    }
+
+    pub fn into_inner(self) -> Result<Output, std::io::Error> {
+        self.csv_writer
+            .into_inner()
+            .map_err(|err| std::io::Error::new(err.error().kind(), err.error().to_string()))
+    }
+
+    fn write(
+        &mut self,
+        position: &csv::Position,
+        row: &str,
+        error: &str,
+        code: &str,
+    ) -> Result<(), csv::Error> {
+        self.csv_writer.serialize(RejectRecord {
+            line: position.line(),
+            byte: position.byte(),
+            record: position.record(),
+            code,
+            error,
+            row,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize, PartialEq, Eq)]
 pub struct Account {
     pub available: Decimal,
     pub held: Decimal,
@@ -262,6 +942,18 @@ pub struct AccountWithClientID<'a> {
     pub account: &'a Account,
 }
 
+impl<'a> AccountWithClientID<'a> {
+    fn columns(&self) -> [String; 5] {
+        [
+            self.client_id.id.to_string(),
+            self.account.available.normalize().to_string(),
+            self.account.held.normalize().to_string(),
+            self.account.total().normalize().to_string(),
+            self.account.locked.to_string(),
+        ]
+    }
+}
+
 impl<'a> Serialize for AccountWithClientID<'a> {
     fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
         use serde::ser::SerializeStruct;
@@ -276,22 +968,190 @@ impl<'a> Serialize for AccountWithClientID<'a> {
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum WithdrawalDisputePolicy {
+    // The disputed amount is moved from available to held, the same way as for deposits
+    #[default]
+    #[serde(rename = "hold")]
+    HoldAvailable,
+    // The disputed withdrawal is provisionally credited back into held, and into available
+    // if it's charged back
+    #[serde(rename = "credit")]
+    CreditHeld,
+    #[serde(rename = "reject")]
+    Reject,
+}
+
+impl std::str::FromStr for WithdrawalDisputePolicy {
+    type Err = String;
+
+    fn from_str(policy: &str) -> Result<WithdrawalDisputePolicy, String> {
+        match policy {
+            "hold" => Ok(WithdrawalDisputePolicy::HoldAvailable),
+            "credit" => Ok(WithdrawalDisputePolicy::CreditHeld),
+            "reject" => Ok(WithdrawalDisputePolicy::Reject),
+            _ => Err(format!("Unknown withdrawal dispute policy {}", policy)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OutOfOrderPolicy {
+    #[default]
+    Accept,
+    Warn,
+    Reject,
+}
+
+impl std::str::FromStr for OutOfOrderPolicy {
+    type Err = String;
+
+    fn from_str(policy: &str) -> Result<OutOfOrderPolicy, String> {
+        match policy {
+            "accept" => Ok(OutOfOrderPolicy::Accept),
+            "warn" => Ok(OutOfOrderPolicy::Warn),
+            "reject" => Ok(OutOfOrderPolicy::Reject),
+            _ => Err(format!("Unknown out of order policy {}", policy)),
+        }
+    }
+}
+
+// Policies aren't part of the saved state, they have to be the same for the runs that share it. Only
+// the withdrawal dispute policy is saved and checked by `try_with_config`.
+#[derive(Debug, Clone, Default)]
+pub struct ProcessorConfig {
+    pub withdrawal_disputes: WithdrawalDisputePolicy,
+    // Transfers with a timestamp can only be disputed within the window and are forgotten after it
+    pub dispute_window: Option<std::time::Duration>,
+    // What to do with transactions that are older than the latest timestamp seen so far
+    pub out_of_order: OutOfOrderPolicy,
+    // Keeps every change of the accounts for the statements
+    pub record_history: bool,
+    // Keeps every ledger posting with the transaction that made it, otherwise only the balances
+    pub record_postings: bool,
+    pub fees: FeeSchedule,
+}
+
 #[derive(Default)]
 pub struct TransactionProcessor {
     pub accounts: std::collections::HashMap<ClientID, Account>,
-    transfers: std::collections::HashMap<TransactionID, Transfer>,
-    in_dispute: std::collections::HashSet<TransactionID>,
-    charged_back: std::collections::HashSet<TransactionID>,
+    store: Box<dyn TransferStore>,
+    admin_actions: Vec<AdminAction>,
+    // IDs of the admin actions, which can't be reused like the IDs of any other new transaction
+    admin_action_ids: std::collections::HashSet<TransactionID>,
+    client_transfers: std::collections::HashMap<TransactionID, ClientTransfer>,
+    fee_transactions: std::collections::HashMap<TransactionID, Fee>,
+    // Total fees charged to every client
+    fees: std::collections::HashMap<ClientID, Decimal>,
+    ledger: Ledger,
+    history: std::collections::HashMap<ClientID, Vec<HistoryEntry>>,
+    observers: Vec<std::sync::Arc<dyn Observer>>,
+    applied_transactions: u64,
+    // The latest timestamp seen so far
+    clock: Option<Timestamp>,
+    // Timestamped transfers in the order they may expire, and the IDs of the expired ones
+    expiry_queue: std::collections::VecDeque<(Timestamp, TransactionID)>,
+    expired: id_set::TransactionIdSet,
+    // Set by a sharded processor while applying a transaction whose ID another shard already knows
+    known_in_other_shard: Option<TransactionID>,
+    // Policy the loaded state was processed with, unknown for new processors and older states
+    saved_withdrawal_disputes: Option<WithdrawalDisputePolicy>,
+    config: ProcessorConfig,
 }
 
 impl TransactionProcessor {
+    pub fn with_config(self, config: ProcessorConfig) -> TransactionProcessor {
+        let mut processor = TransactionProcessor { config, ..self };
+        processor.rebuild_expiry_queue();
+        processor
+    }
+
+    pub fn config(&self) -> &ProcessorConfig {
+        &self.config
+    }
+
+    // Moves the transfers remembered so far into the given store and keeps using it
+    pub fn with_store(mut self, mut store: Box<dyn TransferStore>) -> TransactionProcessor {
+        store::copy_transfers(self.store.as_ref(), store.as_mut());
+        self.store = store;
+        self
+    }
+
     pub fn process(&mut self, transaction: &Transaction) -> Result<(), ProcessingError> {
+        if let Transaction::ClientTransfer(client_transfer) = transaction {
+            return self.process_client_transfer(None, client_transfer);
+        }
+        if !self.observes_accounts() {
+            self.check_timestamp(transaction)?;
+            let change = self.validate(transaction)?;
+            self.commit(change);
+        } else {
+            let before = self
+                .accounts
+                .get(&transaction.client_id())
+                .cloned()
+                .unwrap_or_default();
+            let result = self
+                .check_timestamp(transaction)
+                .and_then(|()| self.validate(transaction))
+                .map(|change| {
+                    let fee = change.fee;
+                    self.commit(change);
+                    fee
+                });
+            self.notify(transaction, transaction.client_id(), &before, &result);
+            result?;
+        }
+        self.applied_transactions += 1;
+        self.advance_clock(transaction);
+        Ok(())
+    }
+
+    pub fn applied_transactions(&self) -> u64 {
+        self.applied_transactions
+    }
+
+    pub fn admin_actions(&self) -> &[AdminAction] {
+        &self.admin_actions
+    }
+
+    pub fn ledger(&self) -> &Ledger {
+        &self.ledger
+    }
+
+    // Stops at the first record that can't be parsed or applied instead of skipping it. The failed
+    // record leaves the processor untouched, the records before it stay applied.
+    pub fn process_strictly(
+        &mut self,
+        input: &mut dyn RecordReader,
+    ) -> Result<(), StrictModeError> {
+        while let Some(record) = input.next_record() {
+            let result = match record.transaction {
+                Ok(transaction) => self
+                    .process(&transaction)
+                    .map_err(RecordError::ProcessingError),
+                Err(err) => Err(RecordError::InputFormatError(err)),
+            };
+            if let Err(error) = result {
+                return Err(StrictModeError {
+                    position: record.position,
+                    row: record.row,
+                    error,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    // Only checks the transaction and works out its effect, so a rejected transaction can't leave
+    // anything changed behind. New transaction types have to keep all the changes in `Change`.
+    fn validate(&self, transaction: &Transaction) -> Result<Change, ProcessingError> {
         match transaction {
             Transaction::Transfer(transfer) => {
-                if self.transfers.contains_key(&transfer.transaction_id) {
+                if self.is_known_transaction(transfer.transaction_id) {
                     return Err(ProcessingError::TransactionIdAlreadyExists);
                 }
-                let mut client_account = self
+                let client_account = self
                     .accounts
                     .get(&transfer.client_id)
                     .cloned()
@@ -300,74 +1160,331 @@ impl TransactionProcessor {
                 if client_account.locked {
                     return Err(ProcessingError::TransferOnLockedAccount);
                 }
-                match transfer.transfer_type {
-                    TransferType::Deposit => client_account.available += transfer.amount,
+                let available = LedgerAccount::Available(transfer.client_id);
+                let fee = self
+                    .config
+                    .fees
+                    .transfer_fee(transfer.transfer_type, transfer.amount);
+                let posting = match transfer.transfer_type {
+                    TransferType::Deposit => {
+                        if client_account.available + transfer.amount < fee {
+                            return Err(ProcessingError::NotEnoughMoneyForFee);
+                        }
+                        Posting::new(LedgerAccount::Cash, available, transfer.amount)
+                    }
                     TransferType::Withdrawal => {
-                        if client_account.available >= transfer.amount {
-                            client_account.available -= transfer.amount;
-                        } else {
+                        if client_account.available < transfer.amount + fee {
                             return Err(ProcessingError::NotEnoughMoneyForWithdrawal);
                         }
+                        Posting::new(available, LedgerAccount::Cash, transfer.amount)
                     }
-                }
-                self.accounts.insert(transfer.client_id, client_account);
-                self.transfers
-                    .insert(transfer.transaction_id, transfer.clone());
-                Ok(())
+                };
+                let mut change = Change {
+                    postings: vec![posting],
+                    new_transfer: Some(transfer.clone()),
+                    ..Change::new(transfer.client_id, transfer.transaction_id, false)
+                };
+                change.charge_fee(fee);
+                Ok(change)
             }
             Transaction::Amendment(amendment) => {
-                let transfer = match self.transfers.get(&amendment.transaction_id) {
+                let transfer = match self.store.transfer(amendment.transaction_id) {
                     Some(transfer) => transfer,
+                    None if self.expired.contains(amendment.transaction_id) => {
+                        return Err(ProcessingError::DisputeWindowExpired)
+                    }
+                    None if self
+                        .client_transfers
+                        .contains_key(&amendment.transaction_id) =>
+                    {
+                        return Err(ProcessingError::DisputingClientTransferIsNotAllowed)
+                    }
+                    None if self
+                        .fee_transactions
+                        .contains_key(&amendment.transaction_id) =>
+                    {
+                        return Err(ProcessingError::DisputingFeeIsNotAllowed)
+                    }
                     None => return Err(ProcessingError::TryingToDisputeUnknownTransaction),
                 };
                 if transfer.client_id != amendment.client_id {
                     return Err(ProcessingError::WrongClientInDispute);
                 }
 
-                let mut client_account = self
+                let client_account = self
                     .accounts
                     .get(&amendment.client_id)
                     .cloned()
                     .expect("Client account must be present for recognised transactions");
+                // Withdrawals credited back while disputed are offset by the disputes clearing
+                let credit_held = transfer.transfer_type == TransferType::Withdrawal
+                    && self.config.withdrawal_disputes == WithdrawalDisputePolicy::CreditHeld;
+                let available = LedgerAccount::Available(amendment.client_id);
+                let held = LedgerAccount::Held(amendment.client_id);
+                let mut change = Change::new(
+                    amendment.client_id,
+                    amendment.transaction_id,
+                    client_account.locked,
+                );
 
                 match amendment.amendment_type {
                     AmendmentType::Dispute => {
-                        if !self.in_dispute.insert(amendment.transaction_id) {
+                        if transfer.transfer_type == TransferType::Withdrawal
+                            && self.config.withdrawal_disputes == WithdrawalDisputePolicy::Reject
+                        {
+                            return Err(ProcessingError::DisputingWithdrawalIsNotAllowed);
+                        }
+                        if self
+                            .store
+                            .disputed_amount(amendment.transaction_id)
+                            .is_some()
+                        {
                             return Err(ProcessingError::TransferIsAlreadyInDispute);
                         }
-                        if self.charged_back.contains(&amendment.transaction_id) {
-                            return Err(ProcessingError::DisputingAlreadyChargedBackTransfer);
+                        if self.is_expired(&transfer, amendment.timestamp) {
+                            return Err(ProcessingError::DisputeWindowExpired);
+                        }
+                        let undisputed_amount = match self
+                            .store
+                            .charged_back_amount(amendment.transaction_id)
+                        {
+                            Some(charged_back) if charged_back >= transfer.amount => {
+                                return Err(ProcessingError::DisputingAlreadyChargedBackTransfer)
+                            }
+                            Some(charged_back) => transfer.amount - charged_back,
+                            None => transfer.amount,
+                        };
+                        let disputed_amount = amendment.amount.unwrap_or(undisputed_amount);
+                        if disputed_amount > undisputed_amount {
+                            return Err(ProcessingError::DisputedAmountExceedsTransfer);
                         }
 
-                        client_account.available -= transfer.amount;
-                        client_account.held += transfer.amount;
+                        change.disputed_amount =
+                            Some((amendment.transaction_id, Some(disputed_amount)));
+                        let source = if credit_held {
+                            LedgerAccount::DisputesClearing
+                        } else {
+                            available
+                        };
+                        change
+                            .postings
+                            .push(Posting::new(source, held, disputed_amount));
                     }
                     AmendmentType::Resolve => {
-                        if !self.in_dispute.remove(&amendment.transaction_id) {
-                            return Err(ProcessingError::ResolvedTransferWasNotInDispute);
-                        }
-
-                        client_account.available += transfer.amount;
-                        client_account.held -= transfer.amount;
+                        let disputed_amount = match self
+                            .store
+                            .disputed_amount(amendment.transaction_id)
+                        {
+                            Some(disputed_amount) => disputed_amount,
+                            None => return Err(ProcessingError::ResolvedTransferWasNotInDispute),
+                        };
+                        change.disputed_amount = Some((amendment.transaction_id, None));
+                        let destination = if credit_held {
+                            LedgerAccount::DisputesClearing
+                        } else {
+                            available
+                        };
+                        change
+                            .postings
+                            .push(Posting::new(held, destination, disputed_amount));
                     }
                     AmendmentType::Chargeback => {
-                        if !self.in_dispute.remove(&amendment.transaction_id) {
-                            return Err(ProcessingError::ChargedBackTransferWasNotInDispute);
+                        let disputed_amount =
+                            match self.store.disputed_amount(amendment.transaction_id) {
+                                Some(disputed_amount) => disputed_amount,
+                                None => {
+                                    return Err(ProcessingError::ChargedBackTransferWasNotInDispute)
+                                }
+                            };
+                        change.disputed_amount = Some((amendment.transaction_id, None));
+                        if credit_held {
+                            // The client gets the withdrawn money back at the processor's expense
+                            change.postings.extend([
+                                Posting::new(held, available, disputed_amount),
+                                Posting::new(
+                                    LedgerAccount::ChargebackLoss,
+                                    LedgerAccount::DisputesClearing,
+                                    disputed_amount,
+                                ),
+                            ]);
+                        } else {
+                            change.postings.push(Posting::new(
+                                held,
+                                LedgerAccount::Cash,
+                                disputed_amount,
+                            ));
                         }
-
-                        client_account.held -= transfer.amount;
-                        client_account.locked = true;
-                        self.charged_back.insert(amendment.transaction_id);
+                        change.locked = true;
+                        // The chargeback itself can't be refused, so its fee only takes what's left
+                        let available_after = client_account.available
+                            + change
+                                .postings
+                                .iter()
+                                .map(|posting| posting.credit_change(available))
+                                .sum::<Decimal>();
+                        change.charge_fee(
+                            self.config
+                                .fees
+                                .chargeback_fee(disputed_amount)
+                                .min(available_after.max(Decimal::zero())),
+                        );
+                        let charged_back = self
+                            .store
+                            .charged_back_amount(amendment.transaction_id)
+                            .unwrap_or_default();
+                        change.charged_back_amount =
+                            Some((amendment.transaction_id, charged_back + disputed_amount));
                     }
                 }
 
+                let held_change = change
+                    .postings
+                    .iter()
+                    .map(|posting| posting.credit_change(held))
+                    .sum::<Decimal>();
                 assert!(
-                    client_account.held >= Decimal::zero(),
+                    client_account.held + held_change >= Decimal::zero(),
                     "We don't expect amount held to go negative in any scenario"
                 );
-                self.accounts.insert(amendment.client_id, client_account);
-                Ok(())
+                Ok(change)
+            }
+            Transaction::AdminAction(admin_action)
+                if self.is_known_transaction(admin_action.transaction_id) =>
+            {
+                Err(ProcessingError::TransactionIdAlreadyExists)
+            }
+            Transaction::AdminAction(admin_action) => match admin_action.action_type {
+                AdminActionType::Unlock => match self.accounts.get(&admin_action.client_id) {
+                    Some(client_account) if client_account.locked => Ok(Change {
+                        admin_action: Some(admin_action.clone()),
+                        ..Change::new(admin_action.client_id, admin_action.transaction_id, false)
+                    }),
+                    _ => Err(ProcessingError::UnlockingAccountThatIsNotLocked),
+                },
+            },
+            Transaction::Fee(fee) => {
+                if self.is_known_transaction(fee.transaction_id) {
+                    return Err(ProcessingError::TransactionIdAlreadyExists);
+                }
+                match self.accounts.get(&fee.client_id) {
+                    Some(client_account) if client_account.available < fee.amount => {
+                        Err(ProcessingError::NotEnoughMoneyForFee)
+                    }
+                    Some(client_account) => {
+                        let mut change = Change {
+                            fee_transaction: Some(fee.clone()),
+                            ..Change::new(fee.client_id, fee.transaction_id, client_account.locked)
+                        };
+                        change.charge_fee(fee.amount);
+                        Ok(change)
+                    }
+                    None => Err(ProcessingError::FeeForUnknownClient),
+                }
+            }
+            Transaction::ClientTransfer(_) => {
+                unreachable!("Both sides of a client transfer are validated separately")
+            }
+        }
+    }
+
+    fn is_known_transaction(&self, transaction_id: TransactionID) -> bool {
+        self.known_in_other_shard == Some(transaction_id)
+            || self.expired.contains(transaction_id)
+            || self.store.transfer(transaction_id).is_some()
+            || self.client_transfers.contains_key(&transaction_id)
+            || self.fee_transactions.contains_key(&transaction_id)
+            || self.admin_action_ids.contains(&transaction_id)
+    }
+
+    // Balances of the client account are derived from the ledger after the postings
+    fn commit(&mut self, change: Change) {
+        for posting in change.postings {
+            if self.config.record_postings {
+                self.ledger
+                    .record(change.client_id, change.transaction_id, posting);
+            } else {
+                self.ledger.post(posting);
             }
         }
+        let (available, held) = self.ledger.client_balances(change.client_id);
+        self.accounts.insert(
+            change.client_id,
+            Account {
+                available,
+                held,
+                locked: change.locked,
+            },
+        );
+        if let Some(transfer) = change.new_transfer {
+            self.store.insert_transfer(transfer);
+        }
+        if let Some((transaction_id, disputed_amount)) = change.disputed_amount {
+            self.store
+                .set_disputed_amount(transaction_id, disputed_amount);
+        }
+        if let Some((transaction_id, charged_back_amount)) = change.charged_back_amount {
+            self.store
+                .set_charged_back_amount(transaction_id, charged_back_amount);
+        }
+        if let Some(admin_action) = change.admin_action {
+            self.admin_action_ids.insert(admin_action.transaction_id);
+            self.admin_actions.push(admin_action);
+        }
+        if let Some(fee) = change.fee {
+            *self.fees.entry(change.client_id).or_default() += fee;
+        }
+        if let Some(client_transfer) = change.client_transfer {
+            self.client_transfers
+                .insert(client_transfer.transaction_id, client_transfer);
+        }
+        if let Some(fee) = change.fee_transaction {
+            self.fee_transactions.insert(fee.transaction_id, fee);
+        }
+    }
+}
+
+// Effect of a validated transaction
+struct Change {
+    client_id: ClientID,
+    transaction_id: TransactionID,
+    postings: Vec<Posting>,
+    locked: bool,
+    new_transfer: Option<Transfer>,
+    disputed_amount: Option<(TransactionID, Option<Decimal>)>,
+    charged_back_amount: Option<(TransactionID, Decimal)>,
+    admin_action: Option<AdminAction>,
+    client_transfer: Option<ClientTransfer>,
+    fee_transaction: Option<Fee>,
+    fee: Option<Decimal>,
+}
+
+impl Change {
+    fn new(client_id: ClientID, transaction_id: TransactionID, locked: bool) -> Change {
+        Change {
+            client_id,
+            transaction_id,
+            postings: Vec::new(),
+            locked,
+            new_transfer: None,
+            disputed_amount: None,
+            charged_back_amount: None,
+            admin_action: None,
+            client_transfer: None,
+            fee_transaction: None,
+            fee: None,
+        }
+    }
+
+    // Fees are paid from the available funds to the house account
+    fn charge_fee(&mut self, fee: Decimal) {
+        if fee.is_zero() {
+            return;
+        }
+        self.postings.push(Posting::new(
+            LedgerAccount::Available(self.client_id),
+            LedgerAccount::House,
+            fee,
+        ));
+        self.fee = Some(fee);
     }
 }
@@ -3,7 +3,360 @@ use tiny_transaction_processor::*;
 
 fn print_usage() {
     info!("Usage:");
-    info!("  tiny-transaction-processor <path-to-transaction-file>");
+    info!("  tiny-transaction-processor [options] <path-to-transaction-file>");
+    info!("");
+    info!("Options:");
+    info!("  --rejects <path>  write the rows that weren't applied to a CSV file");
+    info!("  --state <path>    load the ledger state before processing and save it afterwards");
+    info!("  --journal <path>  journal applied transactions to resume an interrupted run");
+    info!("  --transfer-store <path>");
+    info!(
+        "                    keep the transfers in a file instead of memory, to use with --state"
+    );
+    info!("  --strict          stop at the first row that can't be parsed or applied");
+    info!("  --jobs <count>    process the transactions on multiple threads, sharded by client");
+    info!("  --input-format (csv | jsonl)");
+    info!(
+        "                    format of the transaction file, guessed from the extension by default"
+    );
+    info!("  --withdrawal-disputes (hold | credit | reject)");
+    info!("                    how disputes of withdrawals affect the account, hold by default");
+    info!("  --dispute-window <days>");
+    info!("                    reject disputes of transfers older than the window and forget them");
+    info!("  --out-of-order (accept | warn | reject)");
+    info!(
+        "                    handling of timestamps older than the latest one, accept by default"
+    );
+    info!("  --fee (deposit | withdrawal | chargeback)=<amount>[%]");
+    info!("                    charge a flat or a percentage fee for every such transaction");
+    info!("  --output-format (csv | json | jsonl | table)");
+    info!("                    format of the account output, csv by default");
+    info!("  --sort (client | total | available | held | locked)");
+    info!(
+        "                    ascending order of the accounts in the output, by client by default"
+    );
+    info!("");
+    info!(
+        "  tiny-transaction-processor statement --client <id> [options] <path-to-transaction-file>"
+    );
+    info!("                    print the history of the client's account instead of the accounts");
+    info!("");
+    info!("  tiny-transaction-processor trial-balance [options] <path-to-transaction-file>");
+    info!("                    print the balances of the ledger accounts instead of the accounts");
+    info!("");
+    info!("  tiny-transaction-processor audit --state <path> [--transfer-store <path>]");
+    info!("                    [--withdrawal-disputes (hold | credit | reject)]");
+    info!("                    recompute the accounts of the saved state and report discrepancies");
+    info!("");
+    info!(
+        "  tiny-transaction-processor serve (--tcp <address> | --unix <path> | --http <address>)"
+    );
+    info!("                    [--state <path> [--save-interval <seconds>]]");
+    info!("                    [--withdrawal-disputes ...] [--dispute-window ...]");
+    info!("                    [--out-of-order ...] [--fee ...]");
+    info!("                    apply the transactions received over a socket or HTTP");
+}
+
+enum Command {
+    Process(Options),
+    Serve(ServeOptions),
+    Audit(AuditOptions),
+}
+
+struct ServeOptions {
+    listener: Listener,
+    state: Option<String>,
+    save_interval: std::time::Duration,
+    config: ProcessorConfig,
+}
+
+struct AuditOptions {
+    state: String,
+    transfer_store: Option<String>,
+    // The saved policy is used unless it's given
+    withdrawal_disputes: Option<WithdrawalDisputePolicy>,
+}
+
+enum Listener {
+    Tcp(String),
+    Unix(String),
+    Http(String),
+}
+
+#[derive(Debug, Clone, Copy)]
+enum InputFormat {
+    Csv,
+    JsonLines,
+}
+
+// What is printed once the transactions are processed
+#[derive(Debug)]
+enum Report {
+    Accounts,
+    Statement(ClientID),
+    TrialBalance,
+}
+
+#[derive(Debug)]
+struct Options {
+    input: String,
+    rejects: Option<String>,
+    state: Option<String>,
+    journal: Option<String>,
+    transfer_store: Option<String>,
+    jobs: usize,
+    input_format: Option<InputFormat>,
+    output_format: OutputFormat,
+    sort: AccountOrder,
+    report: Report,
+    strict: bool,
+    config: ProcessorConfig,
+}
+
+impl Default for Options {
+    fn default() -> Options {
+        Options {
+            input: String::new(),
+            rejects: None,
+            state: None,
+            journal: None,
+            transfer_store: None,
+            jobs: 1,
+            input_format: None,
+            output_format: OutputFormat::Csv,
+            sort: AccountOrder::default(),
+            report: Report::Accounts,
+            strict: false,
+            config: ProcessorConfig::default(),
+        }
+    }
+}
+
+fn option_value(option: &str, args: &mut impl Iterator<Item = String>) -> Result<String, String> {
+    args.next()
+        .ok_or_else(|| format!("Missing value after {}", option))
+}
+
+// Options of the processing shared by the commands, returns false for other options
+fn parse_config_option(
+    arg: &str,
+    args: &mut impl Iterator<Item = String>,
+    config: &mut ProcessorConfig,
+) -> Result<bool, String> {
+    match arg {
+        "--withdrawal-disputes" => config.withdrawal_disputes = option_value(arg, args)?.parse()?,
+        "--dispute-window" => {
+            let days: u64 = option_value(arg, args)?
+                .parse()
+                .map_err(|_| "Dispute window must be a number of days".to_owned())?;
+            config.dispute_window = Some(std::time::Duration::from_secs(days * 24 * 60 * 60));
+        }
+        "--out-of-order" => config.out_of_order = option_value(arg, args)?.parse()?,
+        "--fee" => {
+            let fee = option_value(arg, args)?;
+            let (kind, rate) = fee
+                .split_once('=')
+                .ok_or_else(|| format!("Invalid fee {}", fee))?;
+            let rate = Some(rate.parse()?);
+            match kind {
+                "deposit" => config.fees.deposit = rate,
+                "withdrawal" => config.fees.withdrawal = rate,
+                "chargeback" => config.fees.chargeback = rate,
+                _ => return Err(format!("Unknown fee {}", kind)),
+            }
+        }
+        _ => return Ok(false),
+    }
+    Ok(true)
+}
+
+fn parse_options(mut args: impl Iterator<Item = String>) -> Result<Options, String> {
+    let mut input = None;
+    let mut options = Options::default();
+    while let Some(arg) = args.next() {
+        if parse_config_option(&arg, &mut args, &mut options.config)? {
+            continue;
+        }
+        match arg.as_str() {
+            "--strict" => options.strict = true,
+            "--rejects" => options.rejects = Some(option_value(&arg, &mut args)?),
+            "--state" => options.state = Some(option_value(&arg, &mut args)?),
+            "--journal" => options.journal = Some(option_value(&arg, &mut args)?),
+            "--transfer-store" => options.transfer_store = Some(option_value(&arg, &mut args)?),
+            "--input-format" => {
+                options.input_format = match option_value(&arg, &mut args)?.as_str() {
+                    "csv" => Some(InputFormat::Csv),
+                    "jsonl" => Some(InputFormat::JsonLines),
+                    input_format => return Err(format!("Unknown input format {}", input_format)),
+                }
+            }
+            "--output-format" => options.output_format = option_value(&arg, &mut args)?.parse()?,
+            "--sort" => options.sort = option_value(&arg, &mut args)?.parse()?,
+            "--jobs" => {
+                options.jobs = option_value(&arg, &mut args)?
+                    .parse()
+                    .ok()
+                    .filter(|jobs| *jobs > 0)
+                    .ok_or_else(|| "Number of jobs must be a positive integer".to_owned())?
+            }
+            _ if arg.starts_with("--") => return Err(format!("Unknown option {}", arg)),
+            _ if input.is_none() => input = Some(arg),
+            _ => return Err("Only one transaction file is expected".to_owned()),
+        }
+    }
+
+    if options.jobs > 1 && options.journal.is_some() {
+        return Err("Journal can't be used together with multiple jobs".to_owned());
+    }
+    // Every shard keeps its own clock, which only follows the transactions of its clients, so the
+    // transfers would expire differently depending on the number of jobs
+    if options.jobs > 1 && options.config.dispute_window.is_some() {
+        return Err("Dispute window can't be used together with multiple jobs".to_owned());
+    }
+    // Likewise, the shards would only reject the transactions out of order within their clients
+    if options.jobs > 1 && options.config.out_of_order == OutOfOrderPolicy::Reject {
+        return Err(
+            "Rejecting transactions out of order can't be used together with multiple jobs"
+                .to_owned(),
+        );
+    }
+    // Shards keep their transfers in memory, and replaying the journal would apply the transfers
+    // again on top of the store
+    if options.transfer_store.is_some() && (options.jobs > 1 || options.journal.is_some()) {
+        return Err(
+            "Transfer store can't be used together with a journal or multiple jobs".to_owned(),
+        );
+    }
+
+    match input {
+        Some(input) => Ok(Options { input, ..options }),
+        None => Err(
+            "Missing argument! Please provide a path to the CSV file containing transactions"
+                .to_owned(),
+        ),
+    }
+}
+
+fn parse_serve_options(mut args: impl Iterator<Item = String>) -> Result<ServeOptions, String> {
+    let mut listener = None;
+    let mut state = None;
+    let mut save_interval = std::time::Duration::from_secs(10);
+    let mut config = ProcessorConfig::default();
+    while let Some(arg) = args.next() {
+        if parse_config_option(&arg, &mut args, &mut config)? {
+            continue;
+        }
+        let address = match arg.as_str() {
+            "--tcp" => Listener::Tcp(option_value(&arg, &mut args)?),
+            "--unix" => Listener::Unix(option_value(&arg, &mut args)?),
+            "--http" => Listener::Http(option_value(&arg, &mut args)?),
+            "--state" => {
+                state = Some(option_value(&arg, &mut args)?);
+                continue;
+            }
+            "--save-interval" => {
+                save_interval = option_value(&arg, &mut args)?
+                    .parse()
+                    .ok()
+                    .filter(|seconds| *seconds > 0)
+                    .map(std::time::Duration::from_secs)
+                    .ok_or_else(|| {
+                        "Save interval must be a positive number of seconds".to_owned()
+                    })?;
+                continue;
+            }
+            _ => return Err(format!("Unknown option {}", arg)),
+        };
+        if listener.replace(address).is_some() {
+            return Err("Only one address to listen on is expected".to_owned());
+        }
+    }
+    let listener = listener.ok_or_else(|| {
+        "Missing address! Please provide either --tcp, --unix or --http".to_owned()
+    })?;
+    Ok(ServeOptions {
+        listener,
+        state,
+        save_interval,
+        config,
+    })
+}
+
+fn parse_statement_options(mut args: impl Iterator<Item = String>) -> Result<Options, String> {
+    let mut client_id = None;
+    let mut other_args = Vec::new();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--client" => {
+                client_id = Some(
+                    option_value(&arg, &mut args)?
+                        .parse()
+                        .map(ClientID::new)
+                        .map_err(|_| "Client must be a client ID".to_owned())?,
+                )
+            }
+            _ => other_args.push(arg),
+        }
+    }
+    let client_id = client_id.ok_or_else(|| "Missing --client for the statement".to_owned())?;
+    let mut options = parse_options(other_args.into_iter())?;
+    options.report = Report::Statement(client_id);
+    options.config.record_history = true;
+    Ok(options)
+}
+
+fn parse_audit_options(mut args: impl Iterator<Item = String>) -> Result<AuditOptions, String> {
+    let mut state = None;
+    let mut transfer_store = None;
+    let mut withdrawal_disputes = None;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--state" => state = Some(option_value(&arg, &mut args)?),
+            "--transfer-store" => transfer_store = Some(option_value(&arg, &mut args)?),
+            "--withdrawal-disputes" => {
+                withdrawal_disputes = Some(option_value(&arg, &mut args)?.parse()?)
+            }
+            _ => return Err(format!("Unknown option {}", arg)),
+        }
+    }
+    Ok(AuditOptions {
+        state: state.ok_or_else(|| "Missing --state to audit".to_owned())?,
+        transfer_store,
+        withdrawal_disputes,
+    })
+}
+
+fn parse_command(args: impl Iterator<Item = String>) -> Result<Command, String> {
+    let mut args = args.peekable();
+    if args.peek().map(String::as_str) == Some("serve") {
+        args.next();
+        parse_serve_options(args).map(Command::Serve)
+    } else if args.peek().map(String::as_str) == Some("audit") {
+        args.next();
+        parse_audit_options(args).map(Command::Audit)
+    } else if args.peek().map(String::as_str) == Some("statement") {
+        args.next();
+        parse_statement_options(args).map(Command::Process)
+    } else if args.peek().map(String::as_str) == Some("trial-balance") {
+        args.next();
+        parse_options(args).map(|options| {
+            Command::Process(Options {
+                report: Report::TrialBalance,
+                ..options
+            })
+        })
+    } else {
+        parse_options(args).map(Command::Process)
+    }
+}
+
+fn describe_position(position: &csv::Position) -> String {
+    format!(
+        "line {} (byte {}, record {})",
+        position.line(),
+        position.byte(),
+        position.record()
+    )
 }
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
@@ -14,44 +367,418 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         .parse_default_env()
         .init();
 
-    let mut args = std::env::args();
-    match args.len().cmp(&2) {
-        std::cmp::Ordering::Equal => {
-            let filename = args.nth(1).unwrap();
-            info!("Input CSV file: {}", &filename);
-
-            let csv_transactions = CsvReader::from_path(&std::path::Path::new(&filename))?;
-            let mut transaction_processor = TransactionProcessor::default();
-            for transaction in csv_transactions.into_iter() {
-                if let Err(err) = transaction_processor.process(&transaction) {
-                    error!("[ {} ] failed with error {:?}", &transaction, &err);
+    match parse_command(std::env::args().skip(1)) {
+        Ok(Command::Process(options)) => process_file(options),
+        Ok(Command::Serve(options)) => serve(options),
+        Ok(Command::Audit(options)) => audit(options),
+        Err(message) => {
+            error!("{}", message);
+            eprintln!();
+            print_usage();
+
+            Err(std::io::Error::from(std::io::ErrorKind::InvalidInput).into())
+        }
+    }
+}
+
+fn audit(options: AuditOptions) -> Result<(), Box<dyn std::error::Error>> {
+    let state_path = std::path::Path::new(&options.state);
+    info!("Auditing state {}", state_path.display());
+    let mut transaction_processor = match &options.transfer_store {
+        Some(path) => TransactionProcessor::load_state_from_path_with_store(
+            state_path,
+            Box::new(FileTransferStore::open(std::path::Path::new(path))?),
+        )?,
+        None => TransactionProcessor::load_state_from_path(state_path)?,
+    };
+    if let Some(withdrawal_disputes) = options.withdrawal_disputes {
+        transaction_processor = transaction_processor.try_with_config(ProcessorConfig {
+            withdrawal_disputes,
+            ..Default::default()
+        })?;
+    }
+
+    let discrepancies = transaction_processor.verify();
+    for discrepancy in &discrepancies {
+        println!("{}", discrepancy);
+    }
+    if discrepancies.is_empty() {
+        info!(
+            "{} accounts verified, no discrepancies found",
+            transaction_processor.accounts.len()
+        );
+        Ok(())
+    } else {
+        error!("{} discrepancies found", discrepancies.len());
+        Err(std::io::Error::from(std::io::ErrorKind::InvalidData).into())
+    }
+}
+
+// The state is loaded before listening and saved periodically in the background, as the server runs
+// until it's stopped. It's saved once more if the listener fails.
+fn serve(options: ServeOptions) -> Result<(), Box<dyn std::error::Error>> {
+    let state_path = options.state.as_ref().map(std::path::PathBuf::from);
+    let processor = match &state_path {
+        Some(path) if path.exists() => {
+            info!("Loading state from {}", path.display());
+            TransactionProcessor::load_state_from_path(path)?
+        }
+        _ => TransactionProcessor::default(),
+    }
+    .try_with_config(options.config)?;
+    let processor = std::sync::Arc::new(std::sync::Mutex::new(processor));
+    let save_interval = options.save_interval;
+    let saver = state_path.map(|path| {
+        let saver = std::sync::Arc::new(server::StateSaver::new(
+            std::sync::Arc::clone(&processor),
+            path,
+        ));
+        std::sync::Arc::clone(&saver).save_periodically(save_interval);
+        saver
+    });
+
+    let served = listen(processor, options.listener);
+    if let Some(saver) = saver {
+        info!("Saving state");
+        saver.save()?;
+    }
+    served
+}
+
+fn listen(
+    processor: std::sync::Arc<std::sync::Mutex<TransactionProcessor>>,
+    listener: Listener,
+) -> Result<(), Box<dyn std::error::Error>> {
+    match listener {
+        Listener::Tcp(address) => {
+            server::serve_tcp(processor, std::net::TcpListener::bind(address)?)?
+        }
+        Listener::Http(address) => {
+            http::serve_http(processor, std::net::TcpListener::bind(address)?)
+                .map_err(|err| -> Box<dyn std::error::Error> { err })?
+        }
+        #[cfg(unix)]
+        Listener::Unix(path) => {
+            server::serve_unix(processor, std::os::unix::net::UnixListener::bind(path)?)?
+        }
+        #[cfg(not(unix))]
+        Listener::Unix(_) => {
+            error!("Unix sockets are not supported on this platform");
+            return Err(std::io::Error::from(std::io::ErrorKind::Unsupported).into());
+        }
+    }
+    Ok(())
+}
+
+struct RejectReport {
+    writer: Option<RejectWriter<std::fs::File>>,
+}
+
+impl RejectReport {
+    fn input_error(
+        &mut self,
+        position: &csv::Position,
+        row: &str,
+        err: &InputFormatError,
+    ) -> Result<(), csv::Error> {
+        error!("{}: parsing error {:?}", describe_position(position), err);
+        match self.writer.as_mut() {
+            Some(writer) => writer.write_input_error(position, row, err),
+            None => Ok(()),
+        }
+    }
+
+    fn processing_error(
+        &mut self,
+        position: &csv::Position,
+        row: &str,
+        transaction: &Transaction,
+        err: &ProcessingError,
+    ) -> Result<(), csv::Error> {
+        error!(
+            "{}: [ {} ] failed with error {:?}",
+            describe_position(position),
+            transaction,
+            err
+        );
+        match self.writer.as_mut() {
+            Some(writer) => writer.write_processing_error(position, row, err),
+            None => Ok(()),
+        }
+    }
+
+    fn flush(&mut self) -> Result<(), std::io::Error> {
+        match self.writer.as_mut() {
+            Some(writer) => writer.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+fn process_sequentially(
+    input: &mut dyn RecordReader,
+    transaction_processor: &mut TransactionProcessor,
+    mut journal: Option<&mut Journal>,
+    rejects: &mut RejectReport,
+    strict: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    while let Some(record) = input.next_record() {
+        let failure = match record.transaction {
+            Ok(transaction) => match transaction_processor.process(&transaction) {
+                Ok(()) => {
+                    if let Some(journal) = journal.as_mut() {
+                        journal.record_applied(
+                            transaction_processor.applied_transactions(),
+                            &transaction,
+                            input.current_position(),
+                        )?;
+                    }
+                    None
                 }
+                Err(err) => {
+                    rejects.processing_error(&record.position, &record.row, &transaction, &err)?;
+                    Some(RecordError::ProcessingError(err))
+                }
+            },
+            Err(err) => {
+                rejects.input_error(&record.position, &record.row, &err)?;
+                Some(RecordError::InputFormatError(err))
             }
+        };
 
-            let stdout = std::io::stdout();
-            let stdout_lock = stdout.lock();
-            let mut csv_account_writer = csv::Writer::from_writer(stdout_lock);
-            for (client_id, account) in transaction_processor.accounts.iter() {
-                csv_account_writer.serialize(AccountWithClientID { client_id, account })?;
+        if let Some(error) = failure {
+            if strict {
+                return Err(StrictModeError {
+                    position: record.position,
+                    row: record.row,
+                    error,
+                }
+                .into());
             }
+            if let Some(journal) = journal.as_mut() {
+                journal.record_skipped(input.current_position())?;
+            }
+        }
+    }
+    if let Some(journal) = journal {
+        journal.commit()?;
+    }
+    Ok(())
+}
 
-            Ok(())
+// Rejected rows are only known once all the shards are done, they are reported in the input order
+fn process_sharded(
+    input: &mut dyn RecordReader,
+    transaction_processor: TransactionProcessor,
+    jobs: usize,
+    rejects: &mut RejectReport,
+    strict: bool,
+) -> Result<TransactionProcessor, Box<dyn std::error::Error>> {
+    let mut sharded_processor = ShardedProcessor::from_processor(transaction_processor, jobs);
+    let mut input_errors = Vec::new();
+    while let Some(record) = input.next_record() {
+        match record.transaction {
+            Ok(transaction) => sharded_processor.submit((record.position, record.row), transaction),
+            Err(err) => {
+                input_errors.push((record.position, record.row, err));
+                if strict {
+                    break;
+                }
+            }
         }
-        std::cmp::Ordering::Greater => {
-            error!("Only one command line argument is expected");
-            eprintln!();
-            print_usage();
+    }
+    let (transaction_processor, mut rejected) = sharded_processor.finish();
 
-            Err(std::io::Error::from(std::io::ErrorKind::InvalidInput).into())
+    let strict_failure = |position, row, error| -> Box<dyn std::error::Error> {
+        StrictModeError {
+            position,
+            row,
+            error,
         }
-        std::cmp::Ordering::Less => {
-            error!(
-                "Missing argument! Please provide a path to the CSV file containing transactions"
-            );
-            eprintln!();
-            print_usage();
+        .into()
+    };
+    rejected.sort_by_key(|rejected| rejected.tag.0.record());
+    let mut rejected = rejected.into_iter().peekable();
+    for (position, row, err) in input_errors {
+        while let Some(processing_error) =
+            rejected.next_if(|rejected| rejected.tag.0.record() < position.record())
+        {
+            let (position, row) = processing_error.tag;
+            rejects.processing_error(
+                &position,
+                &row,
+                &processing_error.transaction,
+                &processing_error.error,
+            )?;
+            if strict {
+                let error = RecordError::ProcessingError(processing_error.error);
+                return Err(strict_failure(position, row, error));
+            }
+        }
+        rejects.input_error(&position, &row, &err)?;
+        if strict {
+            let error = RecordError::InputFormatError(err);
+            return Err(strict_failure(position, row, error));
+        }
+    }
+    for processing_error in rejected {
+        let (position, row) = processing_error.tag;
+        rejects.processing_error(
+            &position,
+            &row,
+            &processing_error.transaction,
+            &processing_error.error,
+        )?;
+        if strict {
+            let error = RecordError::ProcessingError(processing_error.error);
+            return Err(strict_failure(position, row, error));
+        }
+    }
 
-            Err(std::io::Error::from(std::io::ErrorKind::InvalidInput).into())
+    Ok(transaction_processor)
+}
+
+fn open_input(
+    options: &Options,
+    resume: Option<csv::Position>,
+) -> Result<Box<dyn RecordReader>, Box<dyn std::error::Error>> {
+    let path = std::path::Path::new(&options.input);
+    let input_format = options.input_format.unwrap_or_else(|| {
+        match path.extension().and_then(std::ffi::OsStr::to_str) {
+            Some("jsonl") | Some("ndjson") => InputFormat::JsonLines,
+            _ => InputFormat::Csv,
         }
+    });
+    if let Some(position) = &resume {
+        info!("Resuming after {}", describe_position(position));
     }
+
+    Ok(match input_format {
+        InputFormat::Csv => {
+            let mut reader = CsvReader::from_path(path)?;
+            if let Some(position) = resume {
+                reader.seek(position)?;
+            }
+            Box::new(reader)
+        }
+        InputFormat::JsonLines => {
+            let mut reader = JsonLinesReader::from_path(path)?;
+            if let Some(position) = resume {
+                reader.seek(position)?;
+            }
+            Box::new(reader)
+        }
+    })
+}
+
+fn process_file(options: Options) -> Result<(), Box<dyn std::error::Error>> {
+    info!("Input file: {}", &options.input);
+
+    let state_path = options.state.as_ref().map(std::path::Path::new);
+    let state_exists = state_path.is_some_and(|path| path.exists());
+    let transfer_store = match &options.transfer_store {
+        Some(path) => {
+            let path = std::path::Path::new(path);
+            let mut store = FileTransferStore::open(path)?;
+            // Without a state, only the changes of runs that never saved one can be rolled back
+            if !state_exists {
+                if store.generation() == 0 {
+                    store.restore(0)?;
+                }
+                if store.generation() != 0 || store.transfers().next().is_some() {
+                    return Err(format!(
+                        "Transfer store {} isn't empty, but there's no state to continue from",
+                        path.display()
+                    )
+                    .into());
+                }
+            }
+            Some(Box::new(store) as Box<dyn TransferStore>)
+        }
+        None => None,
+    };
+    let mut transaction_processor = match (state_path, transfer_store) {
+        (Some(path), transfer_store) if state_exists => {
+            info!("Loading state from {}", path.display());
+            match transfer_store {
+                Some(store) => TransactionProcessor::load_state_from_path_with_store(path, store)?,
+                None => TransactionProcessor::load_state_from_path(path)?,
+            }
+        }
+        (_, Some(store)) => TransactionProcessor::default().with_store(store),
+        (_, None) => TransactionProcessor::default(),
+    }
+    .try_with_config(options.config.clone())?;
+    let (mut journal, resume) = match &options.journal {
+        Some(path) => {
+            let (journal, resume) = Journal::open(
+                std::path::Path::new(path),
+                &options.input,
+                &mut transaction_processor,
+            )?;
+            (Some(journal), resume)
+        }
+        None => (None, None),
+    };
+    // A resumed run adds to the rejects of the interrupted one
+    let mut rejects = RejectReport {
+        writer: match &options.rejects {
+            Some(path) if resume.is_some() => {
+                Some(RejectWriter::appending_to_path(std::path::Path::new(path))?)
+            }
+            Some(path) => Some(RejectWriter::from_path(std::path::Path::new(path))?),
+            None => None,
+        },
+    };
+    let mut input = open_input(&options, resume)?;
+
+    let processed = if options.jobs > 1 {
+        process_sharded(
+            input.as_mut(),
+            std::mem::take(&mut transaction_processor),
+            options.jobs,
+            &mut rejects,
+            options.strict,
+        )
+        .map(|processor| transaction_processor = processor)
+    } else {
+        process_sequentially(
+            input.as_mut(),
+            &mut transaction_processor,
+            journal.as_mut(),
+            &mut rejects,
+            options.strict,
+        )
+    };
+    rejects.flush()?;
+    processed?;
+
+    if let Some(path) = state_path {
+        info!("Saving state to {}", path.display());
+        transaction_processor.save_state_to_path(path)?;
+        if let Some(journal) = journal.as_mut() {
+            journal.clear()?;
+        }
+    }
+
+    let stdout = std::io::stdout();
+    match options.report {
+        Report::Accounts => write_accounts(
+            options.output_format,
+            stdout.lock(),
+            transaction_processor.sorted_accounts(options.sort),
+        )?,
+        Report::Statement(client_id) => write_statement(
+            options.output_format,
+            stdout.lock(),
+            transaction_processor.history(client_id),
+        )?,
+        Report::TrialBalance => write_trial_balance(
+            options.output_format,
+            stdout.lock(),
+            &transaction_processor.ledger().trial_balance(),
+        )?,
+    }
+
+    Ok(())
 }
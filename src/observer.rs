@@ -0,0 +1,139 @@
+use std::sync::Arc;
+
+use rust_decimal::Decimal;
+
+use crate::{
+    Account, AdminActionType, AmendmentType, ClientID, ProcessingError, Transaction,
+    TransactionProcessor, TransferType,
+};
+
+#[derive(Debug, Clone, Copy)]
+pub enum AccountEventType<'a> {
+    Deposited(Decimal),
+    Withdrew(Decimal),
+    // Amounts of holds, releases and chargebacks are the change of the held funds
+    FundsHeld(Decimal),
+    FundsReleased(Decimal),
+    ChargedBack(Decimal),
+    AccountLocked,
+    AccountUnlocked,
+    // Sides of a transfer between clients
+    Sent(Decimal),
+    Received(Decimal),
+    FeeCharged(Decimal),
+    Rejected(&'a ProcessingError),
+}
+
+// Snapshots are of the entire transaction, so the events of a chargeback that locked the account
+// share them, except for the fee of the fee schedule: the events before it see the account without
+// the fee, and the fee event takes it from there. A rejected transaction has equal snapshots. A
+// client transfer notifies both clients, each with its own account.
+#[derive(Debug, Clone, Copy)]
+pub struct AccountEvent<'a> {
+    pub client_id: ClientID,
+    pub transaction: &'a Transaction,
+    pub event_type: AccountEventType<'a>,
+    pub before: &'a Account,
+    pub after: &'a Account,
+}
+
+// Observers are shared between the shards of a `ShardedProcessor`, so they may be notified from
+// several threads at once
+pub trait Observer: Send + Sync {
+    fn on_event(&self, event: &AccountEvent);
+}
+
+// Changes of the account caused by an applied transaction, in the order they happened. The fee of
+// the fee schedule is charged last.
+pub(crate) fn applied_events(
+    transaction: &Transaction,
+    client_id: ClientID,
+    before: &Account,
+    after: &Account,
+    fee: Option<Decimal>,
+) -> Vec<AccountEventType<'static>> {
+    let held_change = (after.held - before.held).abs();
+    let mut events = vec![match transaction {
+        Transaction::Transfer(transfer) => match transfer.transfer_type {
+            TransferType::Deposit => AccountEventType::Deposited(transfer.amount),
+            TransferType::Withdrawal => AccountEventType::Withdrew(transfer.amount),
+        },
+        Transaction::Amendment(amendment) => match amendment.amendment_type {
+            AmendmentType::Dispute => AccountEventType::FundsHeld(held_change),
+            AmendmentType::Resolve => AccountEventType::FundsReleased(held_change),
+            AmendmentType::Chargeback => AccountEventType::ChargedBack(held_change),
+        },
+        Transaction::AdminAction(admin_action) => match admin_action.action_type {
+            AdminActionType::Unlock => AccountEventType::AccountUnlocked,
+        },
+        Transaction::ClientTransfer(client_transfer) => {
+            if client_id == client_transfer.client_id {
+                AccountEventType::Sent(client_transfer.amount)
+            } else {
+                AccountEventType::Received(client_transfer.amount)
+            }
+        }
+        Transaction::Fee(fee) => AccountEventType::FeeCharged(fee.amount),
+    }];
+    if after.locked && !before.locked {
+        events.push(AccountEventType::AccountLocked);
+    }
+    match (transaction, fee) {
+        (Transaction::Fee(_), _) | (_, None) => {}
+        (_, Some(fee)) => events.push(AccountEventType::FeeCharged(fee)),
+    }
+    events
+}
+
+impl TransactionProcessor {
+    pub fn with_observer(mut self, observer: Arc<dyn Observer>) -> TransactionProcessor {
+        self.observers.push(observer);
+        self
+    }
+
+    pub(crate) fn observes_accounts(&self) -> bool {
+        self.config.record_history || !self.observers.is_empty()
+    }
+
+    pub(crate) fn notify(
+        &mut self,
+        transaction: &Transaction,
+        client_id: ClientID,
+        before: &Account,
+        // The fee charged by an applied transaction
+        result: &Result<Option<Decimal>, ProcessingError>,
+    ) {
+        let after = self.accounts.get(&client_id).cloned().unwrap_or_default();
+        let before_fee = match (transaction, result) {
+            (Transaction::Fee(_), _) | (_, Ok(None)) | (_, Err(_)) => after.clone(),
+            (_, Ok(Some(fee))) => Account {
+                available: after.available + fee,
+                ..after.clone()
+            },
+        };
+        let events = match result {
+            Ok(fee) => applied_events(transaction, client_id, before, &before_fee, *fee),
+            Err(error) => vec![AccountEventType::Rejected(error)],
+        };
+        for event_type in events {
+            let (before, after) = match (event_type, transaction) {
+                (AccountEventType::FeeCharged(_), Transaction::Fee(_)) => (before, &after),
+                (AccountEventType::FeeCharged(_), _) => (&before_fee, &after),
+                _ => (before, &before_fee),
+            };
+            let event = AccountEvent {
+                client_id,
+                transaction,
+                event_type,
+                before,
+                after,
+            };
+            if self.config.record_history {
+                self.record_history(&event);
+            }
+            for observer in &self.observers {
+                observer.on_event(&event);
+            }
+        }
+    }
+}
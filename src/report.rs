@@ -0,0 +1,309 @@
+use std::io::Write;
+
+use crate::{
+    AccountWithClientID, HistoryEntry, StatementRow, TransactionProcessor, TrialBalanceRow,
+};
+
+// Ties are always broken by client ID, so the order is fully deterministic
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum AccountOrder {
+    #[default]
+    ClientId,
+    Total,
+    Available,
+    Held,
+    Locked,
+}
+
+impl std::str::FromStr for AccountOrder {
+    type Err = String;
+
+    fn from_str(account_order: &str) -> Result<AccountOrder, String> {
+        match account_order {
+            "client" => Ok(AccountOrder::ClientId),
+            "total" => Ok(AccountOrder::Total),
+            "available" => Ok(AccountOrder::Available),
+            "held" => Ok(AccountOrder::Held),
+            "locked" => Ok(AccountOrder::Locked),
+            _ => Err(format!("Unknown account order {}", account_order)),
+        }
+    }
+}
+
+impl TransactionProcessor {
+    pub fn sorted_accounts(
+        &self,
+        order: AccountOrder,
+    ) -> impl Iterator<Item = AccountWithClientID<'_>> {
+        let mut accounts = self
+            .accounts
+            .iter()
+            .map(|(client_id, account)| AccountWithClientID { client_id, account })
+            .collect::<Vec<_>>();
+        match order {
+            AccountOrder::ClientId => accounts.sort_unstable_by_key(|account| *account.client_id),
+            AccountOrder::Total => accounts
+                .sort_unstable_by_key(|account| (account.account.total(), *account.client_id)),
+            AccountOrder::Available => accounts
+                .sort_unstable_by_key(|account| (account.account.available, *account.client_id)),
+            AccountOrder::Held => {
+                accounts.sort_unstable_by_key(|account| (account.account.held, *account.client_id))
+            }
+            AccountOrder::Locked => accounts
+                .sort_unstable_by_key(|account| (account.account.locked, *account.client_id)),
+        }
+        accounts.into_iter()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    Csv,
+    Json,
+    JsonLines,
+    Table,
+}
+
+impl std::str::FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(output_format: &str) -> Result<OutputFormat, String> {
+        match output_format {
+            "csv" => Ok(OutputFormat::Csv),
+            "json" => Ok(OutputFormat::Json),
+            "jsonl" => Ok(OutputFormat::JsonLines),
+            "table" => Ok(OutputFormat::Table),
+            _ => Err(format!("Unknown output format {}", output_format)),
+        }
+    }
+}
+
+impl OutputFormat {
+    pub fn writer<'w, Output: Write + 'w>(self, output: Output) -> Box<dyn AccountWriter + 'w> {
+        match self {
+            OutputFormat::Csv => Box::new(CsvAccountWriter {
+                csv_writer: csv::Writer::from_writer(output),
+            }),
+            OutputFormat::Json => Box::new(JsonAccountWriter {
+                output,
+                accounts_written: 0,
+            }),
+            OutputFormat::JsonLines => Box::new(JsonLinesAccountWriter { output }),
+            OutputFormat::Table => Box::new(TableAccountWriter {
+                output,
+                rows: Vec::new(),
+            }),
+        }
+    }
+}
+
+// Accounts are written one by one, some formats only produce the output on `finish`
+pub trait AccountWriter {
+    fn write_account(&mut self, account: &AccountWithClientID) -> Result<(), std::io::Error>;
+
+    fn finish(&mut self) -> Result<(), std::io::Error>;
+}
+
+pub fn write_accounts<'a>(
+    output_format: OutputFormat,
+    output: impl Write,
+    accounts: impl IntoIterator<Item = AccountWithClientID<'a>>,
+) -> Result<(), std::io::Error> {
+    let mut writer = output_format.writer(output);
+    for account in accounts {
+        writer.write_account(&account)?;
+    }
+    writer.finish()
+}
+
+struct CsvAccountWriter<Output: Write> {
+    csv_writer: csv::Writer<Output>,
+}
+
+impl<Output: Write> AccountWriter for CsvAccountWriter<Output> {
+    fn write_account(&mut self, account: &AccountWithClientID) -> Result<(), std::io::Error> {
+        Ok(self.csv_writer.serialize(account)?)
+    }
+
+    fn finish(&mut self) -> Result<(), std::io::Error> {
+        self.csv_writer.flush()
+    }
+}
+
+struct JsonAccountWriter<Output: Write> {
+    output: Output,
+    accounts_written: usize,
+}
+
+impl<Output: Write> AccountWriter for JsonAccountWriter<Output> {
+    fn write_account(&mut self, account: &AccountWithClientID) -> Result<(), std::io::Error> {
+        let separator = if self.accounts_written == 0 { "[" } else { "," };
+        write!(self.output, "{}\n  ", separator)?;
+        serde_json::to_writer(&mut self.output, account)?;
+        self.accounts_written += 1;
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<(), std::io::Error> {
+        if self.accounts_written == 0 {
+            writeln!(self.output, "[]")?;
+        } else {
+            writeln!(self.output, "\n]")?;
+        }
+        self.output.flush()
+    }
+}
+
+struct JsonLinesAccountWriter<Output: Write> {
+    output: Output,
+}
+
+impl<Output: Write> AccountWriter for JsonLinesAccountWriter<Output> {
+    fn write_account(&mut self, account: &AccountWithClientID) -> Result<(), std::io::Error> {
+        serde_json::to_writer(&mut self.output, account)?;
+        writeln!(self.output)
+    }
+
+    fn finish(&mut self) -> Result<(), std::io::Error> {
+        self.output.flush()
+    }
+}
+
+const TABLE_HEADER: [&str; 5] = ["client", "available", "held", "total", "locked"];
+
+// Column widths depend on all the rows, so the table is only printed on `finish`
+struct TableAccountWriter<Output: Write> {
+    output: Output,
+    rows: Vec<[String; 5]>,
+}
+
+impl<Output: Write> AccountWriter for TableAccountWriter<Output> {
+    fn write_account(&mut self, account: &AccountWithClientID) -> Result<(), std::io::Error> {
+        self.rows.push(account.columns());
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<(), std::io::Error> {
+        write_table(&mut self.output, &TABLE_HEADER, &self.rows)?;
+        self.rows.clear();
+        self.output.flush()
+    }
+}
+
+fn write_table<Row: AsRef<[String]>>(
+    output: &mut impl Write,
+    header: &[&str],
+    rows: &[Row],
+) -> Result<(), std::io::Error> {
+    let mut widths = header.iter().map(|cell| cell.len()).collect::<Vec<_>>();
+    for row in rows {
+        for (width, cell) in widths.iter_mut().zip(row.as_ref()) {
+            *width = (*width).max(cell.len());
+        }
+    }
+
+    let header = header
+        .iter()
+        .map(|cell| cell.to_string())
+        .collect::<Vec<_>>();
+    write_table_row(output, &header, &widths)?;
+    let separator = widths
+        .iter()
+        .map(|width| "-".repeat(*width))
+        .collect::<Vec<_>>();
+    writeln!(output, "-{}", separator.join("-+-"))?;
+    for row in rows {
+        write_table_row(output, row.as_ref(), &widths)?;
+    }
+    Ok(())
+}
+
+fn write_table_row(
+    output: &mut impl Write,
+    row: &[String],
+    widths: &[usize],
+) -> Result<(), std::io::Error> {
+    let cells = row
+        .iter()
+        .zip(widths.iter())
+        .map(|(cell, width)| format!("{:>width$}", cell, width = width))
+        .collect::<Vec<_>>();
+    writeln!(output, " {}", cells.join(" | "))
+}
+
+const STATEMENT_HEADER: [&str; 7] = [
+    "tx",
+    "type",
+    "amount",
+    "available",
+    "held",
+    "total",
+    "locked",
+];
+
+// History of a single client, in the order the events happened
+pub fn write_statement(
+    output_format: OutputFormat,
+    output: impl Write,
+    history: &[HistoryEntry],
+) -> Result<(), std::io::Error> {
+    let rows = history.iter().map(StatementRow).collect::<Vec<_>>();
+    write_report(output_format, output, &STATEMENT_HEADER, &rows, |row| {
+        row.0.columns()
+    })
+}
+
+const TRIAL_BALANCE_HEADER: [&str; 3] = ["account", "debit", "credit"];
+
+pub fn write_trial_balance(
+    output_format: OutputFormat,
+    output: impl Write,
+    trial_balance: &[TrialBalanceRow],
+) -> Result<(), std::io::Error> {
+    write_report(
+        output_format,
+        output,
+        &TRIAL_BALANCE_HEADER,
+        trial_balance,
+        TrialBalanceRow::columns,
+    )
+}
+
+// Reports other than the accounts are small enough to be written all at once
+fn write_report<Row: serde::Serialize, Cells: AsRef<[String]>>(
+    output_format: OutputFormat,
+    mut output: impl Write,
+    header: &[&str],
+    rows: &[Row],
+    columns: impl Fn(&Row) -> Cells,
+) -> Result<(), std::io::Error> {
+    match output_format {
+        OutputFormat::Csv => {
+            let mut csv_writer = csv::Writer::from_writer(output);
+            for row in rows {
+                csv_writer.serialize(row)?;
+            }
+            csv_writer.flush()
+        }
+        OutputFormat::Json => {
+            for (index, row) in rows.iter().enumerate() {
+                write!(output, "{}\n  ", if index == 0 { "[" } else { "," })?;
+                serde_json::to_writer(&mut output, row)?;
+            }
+            writeln!(output, "{}", if rows.is_empty() { "[]" } else { "\n]" })?;
+            output.flush()
+        }
+        OutputFormat::JsonLines => {
+            for row in rows {
+                serde_json::to_writer(&mut output, row)?;
+                writeln!(output)?;
+            }
+            output.flush()
+        }
+        OutputFormat::Table => {
+            let rows = rows.iter().map(columns).collect::<Vec<_>>();
+            write_table(&mut output, header, &rows)?;
+            output.flush()
+        }
+    }
+}
@@ -0,0 +1,189 @@
+use std::io::{BufRead, Write};
+use std::sync::{Arc, Mutex};
+
+use log::{error, info, warn};
+
+use crate::{
+    state, AccountWithClientID, ClientID, CsvReader, RecordReader, StateError, TransactionProcessor,
+};
+
+// Every non-empty line gets exactly one reply line:
+//   deposit, 1, 1, 10  ->  ok
+//   withdrawal, 1, 2, 20  ->  error,E202,NotEnoughMoneyForWithdrawal
+//   query, 1  ->  ok,1,10,0,10,false
+pub fn respond(processor: &Mutex<TransactionProcessor>, line: &str) -> String {
+    if let Some(query) = line.trim().strip_prefix("query") {
+        return respond_to_query(processor, query);
+    }
+
+    let record = match CsvReader::without_headers(line.as_bytes()).next_record() {
+        Some(record) => record,
+        None => return "error,E103,CsvError".to_owned(),
+    };
+    let transaction = match record.transaction {
+        Ok(transaction) => transaction,
+        Err(err) => return format!("error,{},{}", err.code(), err.name()),
+    };
+    let result = processor
+        .lock()
+        .expect("Processor lock is poisoned")
+        .process(&transaction);
+    match result {
+        Ok(()) => "ok".to_owned(),
+        Err(err) => format!("error,{},{}", err.code(), err.name()),
+    }
+}
+
+fn respond_to_query(processor: &Mutex<TransactionProcessor>, query: &str) -> String {
+    let client_id = match query
+        .trim()
+        .strip_prefix(',')
+        .and_then(|client_id| client_id.trim().parse::<u16>().ok())
+    {
+        Some(client_id) => ClientID::new(client_id),
+        None => return "error,E103,CsvError".to_owned(),
+    };
+
+    let processor = processor.lock().expect("Processor lock is poisoned");
+    let account = match processor.accounts.get(&client_id) {
+        Some(account) => account,
+        None => return "error,E301,UnknownClient".to_owned(),
+    };
+
+    let mut row = Vec::new();
+    let mut csv_writer = csv::WriterBuilder::new()
+        .has_headers(false)
+        .from_writer(&mut row);
+    let serialized = csv_writer
+        .serialize(AccountWithClientID {
+            client_id: &client_id,
+            account,
+        })
+        .and_then(|_| Ok(csv_writer.flush()?));
+    drop(csv_writer);
+    match serialized {
+        Ok(()) => format!("ok,{}", String::from_utf8_lossy(&row).trim_end()),
+        Err(err) => {
+            error!("Failed to serialize account {:?}: {:?}", client_id, err);
+            "error,E103,CsvError".to_owned()
+        }
+    }
+}
+
+pub fn handle_connection<Input: std::io::Read, Output: Write>(
+    processor: &Mutex<TransactionProcessor>,
+    input: Input,
+    mut output: Output,
+) -> Result<(), std::io::Error> {
+    for line in std::io::BufReader::new(input).lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        writeln!(output, "{}", respond(processor, &line))?;
+        output.flush()?;
+    }
+    Ok(())
+}
+
+fn spawn_connection<Stream>(processor: &Arc<Mutex<TransactionProcessor>>, stream: Stream)
+where
+    Stream: std::io::Read + Write + Send + 'static,
+    for<'a> &'a Stream: std::io::Read + Write,
+{
+    let processor = Arc::clone(processor);
+    std::thread::spawn(move || {
+        if let Err(err) = handle_connection(&processor, &stream, &stream) {
+            warn!("Connection closed with error {:?}", err);
+        }
+    });
+}
+
+pub fn serve_tcp(
+    processor: Arc<Mutex<TransactionProcessor>>,
+    listener: std::net::TcpListener,
+) -> Result<(), std::io::Error> {
+    info!("Listening on {}", listener.local_addr()?);
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => spawn_connection(&processor, stream),
+            Err(err) => warn!("Failed to accept connection: {:?}", err),
+        }
+    }
+    Ok(())
+}
+
+// Saves the state shared by the connections to a file, skipped if no transaction was applied since
+// the last save. The connections only wait for the state to be serialized, it's written to the file
+// and synced after the processor is unlocked, while the saves themselves are done one at a time.
+// The transfers are expected to be kept in memory, as a persistent store would be committed before
+// the state file is written.
+pub struct StateSaver {
+    processor: Arc<Mutex<TransactionProcessor>>,
+    filepath: std::path::PathBuf,
+    // Applied transactions included in the last save
+    saved: Mutex<u64>,
+}
+
+impl StateSaver {
+    pub fn new(processor: Arc<Mutex<TransactionProcessor>>, filepath: std::path::PathBuf) -> Self {
+        let saved = processor
+            .lock()
+            .expect("Processor lock is poisoned")
+            .applied_transactions();
+        StateSaver {
+            processor,
+            filepath,
+            saved: Mutex::new(saved),
+        }
+    }
+
+    pub fn save(&self) -> Result<(), StateError> {
+        let mut saved = self.saved.lock().expect("State saver lock is poisoned");
+        let mut snapshot = Vec::new();
+        let applied = {
+            let mut processor = self.processor.lock().expect("Processor lock is poisoned");
+            let applied = processor.applied_transactions();
+            if applied == *saved {
+                return Ok(());
+            }
+            processor.save_state(&mut snapshot)?;
+            applied
+        };
+        state::replace_file(&self.filepath, |output| Ok(output.write_all(&snapshot)?))?;
+        *saved = applied;
+        Ok(())
+    }
+
+    // A failed save is only logged and tried again after the next interval
+    pub fn save_periodically(
+        self: Arc<Self>,
+        interval: std::time::Duration,
+    ) -> std::thread::JoinHandle<()> {
+        std::thread::spawn(move || loop {
+            std::thread::sleep(interval);
+            if let Err(err) = self.save() {
+                error!(
+                    "Failed to save state to {}: {:?}",
+                    self.filepath.display(),
+                    err
+                );
+            }
+        })
+    }
+}
+
+#[cfg(unix)]
+pub fn serve_unix(
+    processor: Arc<Mutex<TransactionProcessor>>,
+    listener: std::os::unix::net::UnixListener,
+) -> Result<(), std::io::Error> {
+    info!("Listening on {:?}", listener.local_addr()?);
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => spawn_connection(&processor, stream),
+            Err(err) => warn!("Failed to accept connection: {:?}", err),
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,404 @@
+use std::sync::mpsc;
+
+use crate::id_set::TransactionIdSet;
+use crate::{store, ClientID, ProcessingError, Transaction, TransactionProcessor};
+
+const BATCH_SIZE: usize = 1024;
+const BATCHES_IN_FLIGHT: usize = 16;
+
+#[derive(Debug)]
+pub struct Rejected<Tag> {
+    pub tag: Tag,
+    pub transaction: Transaction,
+    pub error: ProcessingError,
+}
+
+type Batch<Tag> = Vec<(Tag, Transaction)>;
+
+// Processor lent by another shard, which waits for it to be returned
+struct Loan {
+    lent: mpsc::Receiver<TransactionProcessor>,
+    returned: mpsc::SyncSender<TransactionProcessor>,
+}
+
+impl Loan {
+    fn receive(self) -> (TransactionProcessor, mpsc::SyncSender<TransactionProcessor>) {
+        let processor = self
+            .lent
+            .recv()
+            .expect("Shard worker has stopped unexpectedly");
+        (processor, self.returned)
+    }
+}
+
+enum Work<Tag> {
+    Batch(Batch<Tag>),
+    // A transaction applied with the processors lent by other shards: the one of the destination
+    // of a transfer between clients of two shards, and the ones of the shards that got a
+    // transaction with the same ID before
+    Borrow {
+        tag: Tag,
+        transaction: Transaction,
+        destination: Option<Loan>,
+        id_owners: Vec<Loan>,
+    },
+    Lend(
+        mpsc::SyncSender<TransactionProcessor>,
+        mpsc::Receiver<TransactionProcessor>,
+    ),
+}
+
+// Spreads the transactions over worker threads by client ID. Every worker owns a processor
+// for its share of the clients, so transactions of the same client are applied in the order
+// they were submitted. Transfers between clients of different shards pause both of the shards
+// while being applied, and so does a transaction ID reused in another shard, which has to be
+// checked against the transactions of that shard.
+// Every shard has its own clock, advanced by the transactions of its clients only, so a dispute
+// window doesn't expire the transfers, nor the out of order policy applies to the timestamps, the
+// same way as a single processor would.
+pub struct ShardedProcessor<Tag: Send + 'static> {
+    pending: Vec<Batch<Tag>>,
+    // IDs of the transactions every shard got, a bit per ID
+    id_owners: Vec<TransactionIdSet>,
+    senders: Vec<mpsc::SyncSender<Work<Tag>>>,
+    workers: Vec<std::thread::JoinHandle<(TransactionProcessor, Vec<Rejected<Tag>>)>>,
+}
+
+impl<Tag: Send + 'static> ShardedProcessor<Tag> {
+    pub fn new(shards: usize) -> Self {
+        ShardedProcessor::from_processor(TransactionProcessor::default(), shards)
+    }
+
+    pub fn from_processor(processor: TransactionProcessor, shards: usize) -> Self {
+        let shards = shards.max(1);
+        let mut id_owners = vec![TransactionIdSet::default(); shards];
+        for transfer in processor.store.transfers() {
+            id_owners[shard_of_client(transfer.client_id, shards)].insert(transfer.transaction_id);
+        }
+        for fee in processor.fee_transactions.values() {
+            id_owners[shard_of_client(fee.client_id, shards)].insert(fee.transaction_id);
+        }
+        for admin_action in &processor.admin_actions {
+            id_owners[shard_of_client(admin_action.client_id, shards)]
+                .insert(admin_action.transaction_id);
+        }
+        // Both shards of a client transfer keep it, so either of them can tell it's known
+        for client_transfer in processor.client_transfers.values() {
+            id_owners[shard_of_client(client_transfer.client_id, shards)]
+                .insert(client_transfer.transaction_id);
+        }
+
+        let mut senders = Vec::with_capacity(shards);
+        let mut workers = Vec::with_capacity(shards);
+        for mut shard_processor in processor.split(shards) {
+            let (sender, receiver) = mpsc::sync_channel::<Work<Tag>>(BATCHES_IN_FLIGHT);
+            senders.push(sender);
+            workers.push(std::thread::spawn(move || {
+                let mut rejected = Vec::new();
+                for work in receiver {
+                    match work {
+                        Work::Batch(batch) => {
+                            for (tag, transaction) in batch {
+                                if let Err(error) = shard_processor.process(&transaction) {
+                                    rejected.push(Rejected {
+                                        tag,
+                                        transaction,
+                                        error,
+                                    });
+                                }
+                            }
+                        }
+                        Work::Borrow {
+                            tag,
+                            transaction,
+                            destination,
+                            id_owners,
+                        } => {
+                            let mut destination = destination.map(Loan::receive);
+                            let id_owners =
+                                id_owners.into_iter().map(Loan::receive).collect::<Vec<_>>();
+                            let transaction_id = transaction.transaction_id();
+                            if id_owners
+                                .iter()
+                                .any(|(owner, _)| owner.is_known_transaction(transaction_id))
+                            {
+                                shard_processor.known_in_other_shard = Some(transaction_id);
+                            }
+                            let result = match (&transaction, destination.as_mut()) {
+                                (
+                                    Transaction::ClientTransfer(client_transfer),
+                                    Some((destination, _)),
+                                ) => shard_processor
+                                    .process_client_transfer(Some(destination), client_transfer),
+                                _ => shard_processor.process(&transaction),
+                            };
+                            shard_processor.known_in_other_shard = None;
+                            for (processor, returned) in destination.into_iter().chain(id_owners) {
+                                returned
+                                    .send(processor)
+                                    .expect("Shard worker has stopped unexpectedly");
+                            }
+                            if let Err(error) = result {
+                                rejected.push(Rejected {
+                                    tag,
+                                    transaction,
+                                    error,
+                                });
+                            }
+                        }
+                        Work::Lend(lend, returned) => {
+                            lend.send(shard_processor)
+                                .expect("Shard worker has stopped unexpectedly");
+                            shard_processor = returned
+                                .recv()
+                                .expect("Shard worker has stopped unexpectedly");
+                        }
+                    }
+                }
+                (shard_processor, rejected)
+            }));
+        }
+
+        ShardedProcessor {
+            pending: (0..shards)
+                .map(|_| Vec::with_capacity(BATCH_SIZE))
+                .collect(),
+            id_owners,
+            senders,
+            workers,
+        }
+    }
+
+    pub fn shards(&self) -> usize {
+        self.senders.len()
+    }
+
+    pub fn submit(&mut self, tag: Tag, transaction: Transaction) {
+        let shards = self.shards();
+        let shard = shard_of(&transaction, shards);
+        let destination = match &transaction {
+            Transaction::ClientTransfer(client_transfer) => Some(shard_of_client(
+                client_transfer.destination_client_id,
+                shards,
+            ))
+            .filter(|destination| *destination != shard),
+            _ => None,
+        };
+        let id_owners = self.other_id_owners(&transaction, shard, destination);
+        if destination.is_some() || !id_owners.is_empty() {
+            return self.submit_with_loans(tag, transaction, shard, destination, id_owners);
+        }
+        self.pending[shard].push((tag, transaction));
+        if self.pending[shard].len() >= BATCH_SIZE {
+            self.send(shard);
+        }
+    }
+
+    // Waits for all the submitted transactions to be processed and merges the shards back
+    // into a single processor
+    pub fn finish(mut self) -> (TransactionProcessor, Vec<Rejected<Tag>>) {
+        for shard in 0..self.shards() {
+            self.send(shard);
+        }
+        self.senders.clear();
+
+        let mut processor: Option<TransactionProcessor> = None;
+        let mut rejected = Vec::new();
+        for worker in self.workers {
+            let (shard_processor, shard_rejected) =
+                worker.join().expect("Shard worker has panicked");
+            match processor.as_mut() {
+                Some(processor) => processor.merge(shard_processor),
+                None => processor = Some(shard_processor),
+            }
+            rejected.extend(shard_rejected);
+        }
+        (processor.unwrap_or_default(), rejected)
+    }
+
+    // Shards other than the ones applying the transaction that got a transaction with the same new
+    // ID before. The ID is remembered for the shards applying it, whether it's applied or not.
+    fn other_id_owners(
+        &mut self,
+        transaction: &Transaction,
+        shard: usize,
+        destination: Option<usize>,
+    ) -> Vec<usize> {
+        let transaction_id = match transaction {
+            Transaction::Transfer(_)
+            | Transaction::ClientTransfer(_)
+            | Transaction::Fee(_)
+            | Transaction::AdminAction(_) => transaction.transaction_id(),
+            Transaction::Amendment(_) => return Vec::new(),
+        };
+        let applying = [Some(shard), destination];
+        let owners = (0..self.shards())
+            .filter(|owner| !applying.contains(&Some(*owner)))
+            .filter(|owner| self.id_owners[*owner].contains(transaction_id))
+            .collect();
+        for shard in applying.iter().flatten().copied() {
+            self.id_owners[shard].insert(transaction_id);
+        }
+        owners
+    }
+
+    // Every shard gets the transactions in the order they were submitted, so the shards can't end
+    // up waiting for each other
+    fn submit_with_loans(
+        &mut self,
+        tag: Tag,
+        transaction: Transaction,
+        shard: usize,
+        destination: Option<usize>,
+        id_owners: Vec<usize>,
+    ) {
+        self.send(shard);
+        let destination = destination.map(|destination| self.lend(destination));
+        let id_owners = id_owners
+            .into_iter()
+            .map(|owner| self.lend(owner))
+            .collect();
+        self.senders[shard]
+            .send(Work::Borrow {
+                tag,
+                transaction,
+                destination,
+                id_owners,
+            })
+            .expect("Shard worker has stopped unexpectedly");
+    }
+
+    fn lend(&mut self, shard: usize) -> Loan {
+        self.send(shard);
+        let (lend, lent) = mpsc::sync_channel(1);
+        let (give_back, returned) = mpsc::sync_channel(1);
+        self.senders[shard]
+            .send(Work::Lend(lend, returned))
+            .expect("Shard worker has stopped unexpectedly");
+        Loan {
+            lent,
+            returned: give_back,
+        }
+    }
+
+    fn send(&mut self, shard: usize) {
+        if self.pending[shard].is_empty() {
+            return;
+        }
+        let batch = std::mem::replace(&mut self.pending[shard], Vec::with_capacity(BATCH_SIZE));
+        self.senders[shard]
+            .send(Work::Batch(batch))
+            .expect("Shard worker has stopped unexpectedly");
+    }
+}
+
+fn shard_of(transaction: &Transaction, shards: usize) -> usize {
+    shard_of_client(transaction.client_id(), shards)
+}
+
+fn shard_of_client(client_id: ClientID, shards: usize) -> usize {
+    client_id.id as usize % shards
+}
+
+impl TransactionProcessor {
+    pub fn split(self, shards: usize) -> Vec<TransactionProcessor> {
+        let shards = shards.max(1);
+        let mut processors = (0..shards)
+            .map(|_| TransactionProcessor::default().with_config(self.config.clone()))
+            .collect::<Vec<_>>();
+        processors[0].applied_transactions = self.applied_transactions;
+        for (processor, ledger) in processors.iter_mut().zip(
+            self.ledger
+                .split(|client_id| shard_of_client(client_id, shards), shards),
+        ) {
+            processor.ledger = ledger;
+        }
+        for processor in &mut processors {
+            processor.observers = self.observers.clone();
+            processor.clock = self.clock;
+            processor.expired = self.expired.clone();
+        }
+
+        for (client_id, account) in self.accounts {
+            processors[shard_of_client(client_id, shards)]
+                .accounts
+                .insert(client_id, account);
+        }
+        for (client_id, history) in self.history {
+            processors[shard_of_client(client_id, shards)]
+                .history
+                .insert(client_id, history);
+        }
+        for (client_id, fees) in self.fees {
+            processors[shard_of_client(client_id, shards)]
+                .fees
+                .insert(client_id, fees);
+        }
+        for (transaction_id, fee) in self.fee_transactions {
+            processors[shard_of_client(fee.client_id, shards)]
+                .fee_transactions
+                .insert(transaction_id, fee);
+        }
+        for admin_action in self.admin_actions {
+            let processor = &mut processors[shard_of_client(admin_action.client_id, shards)];
+            processor
+                .admin_action_ids
+                .insert(admin_action.transaction_id);
+            processor.admin_actions.push(admin_action);
+        }
+        // Both shards of a client transfer keep it
+        for (transaction_id, client_transfer) in self.client_transfers {
+            let destination = shard_of_client(client_transfer.destination_client_id, shards);
+            processors[destination]
+                .client_transfers
+                .insert(transaction_id, client_transfer.clone());
+            processors[shard_of_client(client_transfer.client_id, shards)]
+                .client_transfers
+                .insert(transaction_id, client_transfer);
+        }
+        for (timestamp, transaction_id) in self.expiry_queue {
+            if let Some(transfer) = self.store.transfer(transaction_id) {
+                processors[shard_of_client(transfer.client_id, shards)]
+                    .expiry_queue
+                    .push_back((timestamp, transaction_id));
+            }
+        }
+        for transfer in self.store.transfers() {
+            let transaction_id = transfer.transaction_id;
+            let processor = &mut processors[shard_of_client(transfer.client_id, shards)];
+            processor.store.insert_transfer(transfer);
+            if let Some(amount) = self.store.disputed_amount(transaction_id) {
+                processor
+                    .store
+                    .set_disputed_amount(transaction_id, Some(amount));
+            }
+            if let Some(amount) = self.store.charged_back_amount(transaction_id) {
+                processor
+                    .store
+                    .set_charged_back_amount(transaction_id, amount);
+            }
+        }
+        processors
+    }
+
+    // Clients of the merged processors are expected not to overlap. Observers are shared by the
+    // shards, so only the ones of `self` are kept.
+    pub fn merge(&mut self, other: TransactionProcessor) {
+        self.accounts.extend(other.accounts);
+        store::copy_transfers(other.store.as_ref(), self.store.as_mut());
+        self.admin_actions.extend(other.admin_actions);
+        self.admin_action_ids.extend(other.admin_action_ids);
+        self.client_transfers.extend(other.client_transfers);
+        self.fee_transactions.extend(other.fee_transactions);
+        self.fees.extend(other.fees);
+        self.ledger.merge(other.ledger);
+        self.history.extend(other.history);
+        self.applied_transactions += other.applied_transactions;
+        self.clock = self.clock.max(other.clock);
+        self.expiry_queue.extend(other.expiry_queue);
+        self.expiry_queue
+            .make_contiguous()
+            .sort_unstable_by_key(|(timestamp, _)| *timestamp);
+        self.expired.extend(other.expired.iter());
+    }
+}
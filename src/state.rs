@@ -0,0 +1,357 @@
+use std::collections::{HashMap, HashSet};
+
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize, Serializer};
+
+use crate::id_set::TransactionIdSet;
+use crate::{
+    Account, AdminAction, ClientID, ClientTransfer, Fee, HistoryEntry, Ledger, ProcessorConfig,
+    Timestamp, TransactionID, TransactionProcessor, Transfer, TransferStore,
+    WithdrawalDisputePolicy,
+};
+
+// Version 1 kept only the IDs of the disputed and charged back transfers, as the entire transfer was
+// always disputed. It's still loaded by assuming the full transfer amounts.
+const STATE_FORMAT_VERSION: u32 = 2;
+
+#[derive(Debug)]
+pub enum StateError {
+    UnsupportedVersion(u32),
+    // The transfers were saved in a persistent store, which has to be provided for loading
+    TransferStoreRequired,
+    // The persistent store was committed with another state than the one being loaded
+    TransferStoreMismatch {
+        state: u64,
+        store: u64,
+    },
+    // Disputes of withdrawals were posted with the saved policy, so they can't be settled with another
+    WithdrawalDisputePolicyMismatch {
+        saved: WithdrawalDisputePolicy,
+        configured: WithdrawalDisputePolicy,
+    },
+    IoError(std::io::Error),
+    JsonError(serde_json::Error),
+}
+
+impl std::convert::From<std::io::Error> for StateError {
+    fn from(io_error: std::io::Error) -> StateError {
+        StateError::IoError(io_error)
+    }
+}
+
+impl std::convert::From<serde_json::Error> for StateError {
+    fn from(json_error: serde_json::Error) -> StateError {
+        StateError::JsonError(json_error)
+    }
+}
+
+impl std::fmt::Display for StateError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl std::error::Error for StateError {}
+
+#[derive(Deserialize)]
+struct StateVersion {
+    version: u32,
+}
+
+// Contents of a transfer store, serialized as maps by transaction ID without collecting them first
+enum StoreEntries<'a> {
+    Transfers(&'a dyn TransferStore),
+    InDispute(&'a dyn TransferStore),
+    ChargedBack(&'a dyn TransferStore),
+    Omitted,
+}
+
+impl Serialize for StoreEntries<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            StoreEntries::Transfers(store) => serializer.collect_map(
+                store
+                    .transfers()
+                    .map(|transfer| (transfer.transaction_id, transfer)),
+            ),
+            StoreEntries::InDispute(store) => serializer.collect_map(store.disputed_amounts()),
+            StoreEntries::ChargedBack(store) => {
+                serializer.collect_map(store.charged_back_amounts())
+            }
+            StoreEntries::Omitted => serializer.collect_map(std::iter::empty::<(u32, u32)>()),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct StateRef<'a> {
+    version: u32,
+    accounts: &'a HashMap<ClientID, Account>,
+    transfers_in_store: bool,
+    store_generation: u64,
+    transfers: StoreEntries<'a>,
+    in_dispute: StoreEntries<'a>,
+    charged_back: StoreEntries<'a>,
+    admin_actions: &'a [AdminAction],
+    client_transfers: &'a HashMap<TransactionID, ClientTransfer>,
+    fee_transactions: &'a HashMap<TransactionID, Fee>,
+    fees: &'a HashMap<ClientID, Decimal>,
+    ledger: &'a Ledger,
+    history: &'a HashMap<ClientID, Vec<HistoryEntry>>,
+    applied_transactions: u64,
+    clock: Option<Timestamp>,
+    expired: &'a TransactionIdSet,
+    withdrawal_disputes: WithdrawalDisputePolicy,
+}
+
+#[derive(Deserialize)]
+struct State<Disputes> {
+    accounts: HashMap<ClientID, Account>,
+    #[serde(default)]
+    transfers_in_store: bool,
+    #[serde(default)]
+    store_generation: u64,
+    transfers: HashMap<TransactionID, Transfer>,
+    in_dispute: Disputes,
+    charged_back: Disputes,
+    #[serde(default)]
+    admin_actions: Vec<AdminAction>,
+    #[serde(default)]
+    client_transfers: HashMap<TransactionID, ClientTransfer>,
+    #[serde(default)]
+    fee_transactions: HashMap<TransactionID, Fee>,
+    #[serde(default)]
+    fees: HashMap<ClientID, Decimal>,
+    // States saved before the ledger only have the accounts
+    #[serde(default)]
+    ledger: Option<Ledger>,
+    #[serde(default)]
+    history: HashMap<ClientID, Vec<HistoryEntry>>,
+    #[serde(default)]
+    applied_transactions: u64,
+    #[serde(default)]
+    clock: Option<Timestamp>,
+    #[serde(default)]
+    expired: TransactionIdSet,
+    #[serde(default)]
+    withdrawal_disputes: Option<WithdrawalDisputePolicy>,
+}
+
+impl TransactionProcessor {
+    // Transfers of a persistent store are flushed to it instead of being saved with the state, and
+    // the store is committed with the next generation once the state is written
+    pub fn save_state<Output: std::io::Write>(&mut self, output: Output) -> Result<(), StateError> {
+        let store_generation = self.write_state(output)?;
+        self.store.commit(store_generation)?;
+        Ok(())
+    }
+
+    fn write_state<Output: std::io::Write>(&mut self, output: Output) -> Result<u64, StateError> {
+        let transfers_in_store = self.store.is_persistent();
+        let store_generation = if transfers_in_store {
+            self.store.flush()?;
+            self.store.generation() + 1
+        } else {
+            0
+        };
+        let store = if transfers_in_store {
+            None
+        } else {
+            Some(self.store.as_ref())
+        };
+        serde_json::to_writer(
+            output,
+            &StateRef {
+                version: STATE_FORMAT_VERSION,
+                accounts: &self.accounts,
+                transfers_in_store,
+                store_generation,
+                transfers: store.map_or(StoreEntries::Omitted, StoreEntries::Transfers),
+                in_dispute: store.map_or(StoreEntries::Omitted, StoreEntries::InDispute),
+                charged_back: store.map_or(StoreEntries::Omitted, StoreEntries::ChargedBack),
+                admin_actions: &self.admin_actions,
+                client_transfers: &self.client_transfers,
+                fee_transactions: &self.fee_transactions,
+                fees: &self.fees,
+                ledger: &self.ledger,
+                history: &self.history,
+                applied_transactions: self.applied_transactions,
+                clock: self.clock,
+                expired: &self.expired,
+                withdrawal_disputes: self.config.withdrawal_disputes,
+            },
+        )?;
+        Ok(store_generation)
+    }
+
+    pub fn load_state<Input: std::io::Read>(
+        input: Input,
+    ) -> Result<TransactionProcessor, StateError> {
+        TransactionProcessor::load_state_with_store(input, Box::<dyn TransferStore>::default())
+    }
+
+    // Transfers saved with the state are inserted into the given store. If they were kept in a
+    // persistent store, it's expected to be the same one they were flushed to, and it's brought
+    // back to the generation of the state, dropping the changes of a run that didn't save it.
+    pub fn load_state_with_store<Input: std::io::Read>(
+        mut input: Input,
+        mut store: Box<dyn TransferStore>,
+    ) -> Result<TransactionProcessor, StateError> {
+        let mut serialized_state = Vec::new();
+        input.read_to_end(&mut serialized_state)?;
+
+        let StateVersion { version } = serde_json::from_slice(&serialized_state)?;
+        let state: State<HashMap<TransactionID, Decimal>> = match version {
+            1 => {
+                let state: State<HashSet<TransactionID>> =
+                    serde_json::from_slice(&serialized_state)?;
+                let transfers = &state.transfers;
+                let entire_transfers = |transaction_ids: HashSet<TransactionID>| {
+                    transaction_ids
+                        .into_iter()
+                        .filter_map(|transaction_id| {
+                            let transfer = transfers.get(&transaction_id)?;
+                            Some((transaction_id, transfer.amount))
+                        })
+                        .collect()
+                };
+                State {
+                    in_dispute: entire_transfers(state.in_dispute),
+                    charged_back: entire_transfers(state.charged_back),
+                    accounts: state.accounts,
+                    transfers_in_store: state.transfers_in_store,
+                    store_generation: state.store_generation,
+                    transfers: state.transfers,
+                    admin_actions: state.admin_actions,
+                    client_transfers: state.client_transfers,
+                    fee_transactions: state.fee_transactions,
+                    fees: state.fees,
+                    ledger: state.ledger,
+                    history: state.history,
+                    applied_transactions: state.applied_transactions,
+                    clock: state.clock,
+                    expired: state.expired,
+                    withdrawal_disputes: state.withdrawal_disputes,
+                }
+            }
+            STATE_FORMAT_VERSION => serde_json::from_slice(&serialized_state)?,
+            _ => return Err(StateError::UnsupportedVersion(version)),
+        };
+        if state.transfers_in_store && !store.is_persistent() {
+            return Err(StateError::TransferStoreRequired);
+        }
+        // The store is a generation behind, if the state was saved but the commit was interrupted
+        let state_generation = if state.transfers_in_store {
+            state.store_generation
+        } else {
+            0
+        };
+        let store_generation = store.generation();
+        if store_generation != state_generation && store_generation + 1 != state_generation {
+            return Err(StateError::TransferStoreMismatch {
+                state: state_generation,
+                store: store_generation,
+            });
+        }
+        store.restore(state_generation)?;
+        for (_, transfer) in state.transfers {
+            store.insert_transfer(transfer);
+        }
+        for (transaction_id, amount) in state.in_dispute {
+            store.set_disputed_amount(transaction_id, Some(amount));
+        }
+        for (transaction_id, amount) in state.charged_back {
+            store.set_charged_back_amount(transaction_id, amount);
+        }
+        let ledger = match state.ledger {
+            Some(ledger) => ledger,
+            None => Ledger::opening(&state.accounts),
+        };
+        Ok(TransactionProcessor {
+            accounts: state.accounts,
+            store,
+            admin_action_ids: state
+                .admin_actions
+                .iter()
+                .map(|admin_action| admin_action.transaction_id)
+                .collect(),
+            admin_actions: state.admin_actions,
+            client_transfers: state.client_transfers,
+            fee_transactions: state.fee_transactions,
+            fees: state.fees,
+            ledger,
+            history: state.history,
+            observers: Vec::new(),
+            applied_transactions: state.applied_transactions,
+            clock: state.clock,
+            expiry_queue: Default::default(),
+            expired: state.expired,
+            known_in_other_shard: None,
+            saved_withdrawal_disputes: state.withdrawal_disputes,
+            config: ProcessorConfig {
+                withdrawal_disputes: state.withdrawal_disputes.unwrap_or_default(),
+                ..Default::default()
+            },
+        })
+    }
+
+    // Like `with_config`, but refuses to continue a loaded state with a policy that settles the
+    // disputes of withdrawals differently than the one it was saved with
+    pub fn try_with_config(
+        self,
+        config: ProcessorConfig,
+    ) -> Result<TransactionProcessor, StateError> {
+        let credits_held = |policy| policy == WithdrawalDisputePolicy::CreditHeld;
+        match self.saved_withdrawal_disputes {
+            Some(saved) if credits_held(saved) != credits_held(config.withdrawal_disputes) => {
+                Err(StateError::WithdrawalDisputePolicyMismatch {
+                    saved,
+                    configured: config.withdrawal_disputes,
+                })
+            }
+            _ => Ok(self.with_config(config)),
+        }
+    }
+
+    // The store is committed only after the state file is replaced, as until then the previous
+    // state is the one to load
+    pub fn save_state_to_path(&mut self, filepath: &std::path::Path) -> Result<(), StateError> {
+        let store_generation = replace_file(filepath, |output| self.write_state(output))?;
+        self.store.commit(store_generation)?;
+        Ok(())
+    }
+
+    pub fn load_state_from_path(
+        filepath: &std::path::Path,
+    ) -> Result<TransactionProcessor, StateError> {
+        TransactionProcessor::load_state(std::io::BufReader::new(std::fs::File::open(filepath)?))
+    }
+
+    pub fn load_state_from_path_with_store(
+        filepath: &std::path::Path,
+        store: Box<dyn TransferStore>,
+    ) -> Result<TransactionProcessor, StateError> {
+        TransactionProcessor::load_state_with_store(
+            std::io::BufReader::new(std::fs::File::open(filepath)?),
+            store,
+        )
+    }
+}
+
+// The file is written next to the destination first and then renamed over it, so a crash while
+// writing never leaves a truncated file behind
+pub(crate) fn replace_file<T>(
+    filepath: &std::path::Path,
+    write: impl FnOnce(&mut std::io::BufWriter<std::fs::File>) -> Result<T, StateError>,
+) -> Result<T, StateError> {
+    let mut temporary_path = filepath.as_os_str().to_owned();
+    temporary_path.push(".tmp");
+    let temporary_path = std::path::PathBuf::from(temporary_path);
+
+    let mut output = std::io::BufWriter::new(std::fs::File::create(&temporary_path)?);
+    let written = write(&mut output)?;
+    let file = output.into_inner().map_err(|err| err.into_error())?;
+    file.sync_all()?;
+    std::fs::rename(&temporary_path, filepath)?;
+    Ok(written)
+}
@@ -0,0 +1,129 @@
+use std::collections::HashMap;
+
+use rust_decimal::Decimal;
+
+use crate::{CompactTransferStore, TransactionID, Transfer};
+
+// Everything the processor remembers about the transfers: the transfers themselves, so they can be
+// disputed, the amounts held by the open disputes and the amounts charged back so far
+pub trait TransferStore: Send {
+    fn transfer(&self, transaction_id: TransactionID) -> Option<Transfer>;
+
+    fn insert_transfer(&mut self, transfer: Transfer);
+
+    // Forgets the dispute and chargeback state of the transfer as well
+    fn remove_transfer(&mut self, transaction_id: TransactionID);
+
+    fn disputed_amount(&self, transaction_id: TransactionID) -> Option<Decimal>;
+
+    fn set_disputed_amount(&mut self, transaction_id: TransactionID, amount: Option<Decimal>);
+
+    fn charged_back_amount(&self, transaction_id: TransactionID) -> Option<Decimal>;
+
+    fn set_charged_back_amount(&mut self, transaction_id: TransactionID, amount: Decimal);
+
+    fn transfers(&self) -> Box<dyn Iterator<Item = Transfer> + '_>;
+
+    fn disputed_amounts(&self) -> Box<dyn Iterator<Item = (TransactionID, Decimal)> + '_>;
+
+    fn charged_back_amounts(&self) -> Box<dyn Iterator<Item = (TransactionID, Decimal)> + '_>;
+
+    // Persistent stores keep the transfers on their own, so they aren't a part of the saved state
+    fn is_persistent(&self) -> bool {
+        false
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    // Persistent stores are committed together with the saved state, which keeps the generation of
+    // the last commit, so a store and a state that don't belong together can be told apart
+    fn generation(&self) -> u64 {
+        0
+    }
+
+    // Makes the flushed changes a part of the given generation, once the state saying so is saved
+    fn commit(&mut self, _generation: u64) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    // Brings the store to the generation of the state being loaded, either the store's own or the
+    // next one: rolls back the changes made since the last commit, or completes a commit that was
+    // interrupted after the state was saved
+    fn restore(&mut self, _generation: u64) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+pub(crate) fn copy_transfers(from: &dyn TransferStore, to: &mut dyn TransferStore) {
+    for transfer in from.transfers() {
+        to.insert_transfer(transfer);
+    }
+    for (transaction_id, amount) in from.disputed_amounts() {
+        to.set_disputed_amount(transaction_id, Some(amount));
+    }
+    for (transaction_id, amount) in from.charged_back_amounts() {
+        to.set_charged_back_amount(transaction_id, amount);
+    }
+}
+
+impl Default for Box<dyn TransferStore> {
+    fn default() -> Self {
+        Box::new(CompactTransferStore::default())
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct MemoryTransferStore {
+    transfers: HashMap<TransactionID, Transfer>,
+    in_dispute: HashMap<TransactionID, Decimal>,
+    charged_back: HashMap<TransactionID, Decimal>,
+}
+
+impl TransferStore for MemoryTransferStore {
+    fn transfer(&self, transaction_id: TransactionID) -> Option<Transfer> {
+        self.transfers.get(&transaction_id).cloned()
+    }
+
+    fn insert_transfer(&mut self, transfer: Transfer) {
+        self.transfers.insert(transfer.transaction_id, transfer);
+    }
+
+    fn remove_transfer(&mut self, transaction_id: TransactionID) {
+        self.transfers.remove(&transaction_id);
+        self.in_dispute.remove(&transaction_id);
+        self.charged_back.remove(&transaction_id);
+    }
+
+    fn disputed_amount(&self, transaction_id: TransactionID) -> Option<Decimal> {
+        self.in_dispute.get(&transaction_id).copied()
+    }
+
+    fn set_disputed_amount(&mut self, transaction_id: TransactionID, amount: Option<Decimal>) {
+        match amount {
+            Some(amount) => self.in_dispute.insert(transaction_id, amount),
+            None => self.in_dispute.remove(&transaction_id),
+        };
+    }
+
+    fn charged_back_amount(&self, transaction_id: TransactionID) -> Option<Decimal> {
+        self.charged_back.get(&transaction_id).copied()
+    }
+
+    fn set_charged_back_amount(&mut self, transaction_id: TransactionID, amount: Decimal) {
+        self.charged_back.insert(transaction_id, amount);
+    }
+
+    fn transfers(&self) -> Box<dyn Iterator<Item = Transfer> + '_> {
+        Box::new(self.transfers.values().cloned())
+    }
+
+    fn disputed_amounts(&self) -> Box<dyn Iterator<Item = (TransactionID, Decimal)> + '_> {
+        Box::new(self.in_dispute.iter().map(|(id, amount)| (*id, *amount)))
+    }
+
+    fn charged_back_amounts(&self) -> Box<dyn Iterator<Item = (TransactionID, Decimal)> + '_> {
+        Box::new(self.charged_back.iter().map(|(id, amount)| (*id, *amount)))
+    }
+}
@@ -27,6 +27,7 @@ impl TransactionGenerator {
             client_id,
             amount: amount.abs(),
             transfer_type,
+            timestamp: None,
         })
     }
 
@@ -39,6 +40,8 @@ impl TransactionGenerator {
             client_id,
             transaction_id,
             amendment_type: AmendmentType::Dispute,
+            amount: None,
+            timestamp: None,
         })
     }
 
@@ -51,6 +54,8 @@ impl TransactionGenerator {
             client_id,
             transaction_id,
             amendment_type: AmendmentType::Resolve,
+            amount: None,
+            timestamp: None,
         })
     }
 
@@ -63,6 +68,25 @@ impl TransactionGenerator {
             client_id,
             transaction_id,
             amendment_type: AmendmentType::Chargeback,
+            amount: None,
+            timestamp: None,
+        })
+    }
+
+    fn client_transfer(
+        &mut self,
+        client_id: ClientID,
+        destination_client_id: ClientID,
+        amount: Decimal,
+    ) -> Transaction {
+        self.transaction_count += 1;
+        Transaction::ClientTransfer(ClientTransfer {
+            transfer_type: ClientTransferType::Transfer,
+            client_id,
+            destination_client_id,
+            transaction_id: TransactionID::new(self.transaction_count),
+            amount,
+            timestamp: None,
         })
     }
 }
@@ -105,6 +129,8 @@ fn test_dispute() {
         amendment_type: AmendmentType::Dispute,
         client_id: ClientID::new(72),
         transaction_id: deposit.transaction_id(),
+        amount: None,
+        timestamp: None,
     });
 
     assert!(processor.process(&dispute_with_wrong_client).is_err());
@@ -115,6 +141,8 @@ fn test_dispute() {
         amendment_type: AmendmentType::Dispute,
         client_id,
         transaction_id: TransactionID::new(42),
+        amount: None,
+        timestamp: None,
     });
 
     assert!(processor
@@ -146,6 +174,295 @@ fn test_dispute() {
     assert!(!state_with_two_disputes.locked);
 }
 
+#[test]
+fn test_withdrawal_dispute_policies() {
+    let run = |withdrawal_disputes| {
+        let mut generator = TransactionGenerator::default();
+        let mut processor = TransactionProcessor::default().with_config(ProcessorConfig {
+            withdrawal_disputes,
+            ..Default::default()
+        });
+        let client_id = ClientID::new(23);
+        let withdrawal = generator.transfer(client_id, dec!(-4));
+        assert!(processor
+            .process(&generator.transfer(client_id, dec!(10)))
+            .is_ok());
+        assert!(processor.process(&withdrawal).is_ok());
+
+        let mut balances = Vec::new();
+        let mut balance = |processor: &TransactionProcessor| {
+            let account = &processor.accounts[&client_id];
+            balances.push((account.available, account.held, account.locked));
+        };
+        let dispute = generator.dispute(withdrawal.transaction_id());
+        if processor.process(&dispute).is_err() {
+            balance(&processor);
+            return balances;
+        }
+        balance(&processor);
+        assert!(processor
+            .process(&generator.resolve(withdrawal.transaction_id()))
+            .is_ok());
+        balance(&processor);
+        assert!(processor.process(&dispute).is_ok());
+        assert!(processor
+            .process(&generator.chargeback(withdrawal.transaction_id()))
+            .is_ok());
+        balance(&processor);
+        balances
+    };
+
+    // Disputed, resolved, then disputed again and charged back
+    assert_eq!(
+        run(WithdrawalDisputePolicy::HoldAvailable),
+        [
+            (dec!(2), dec!(4), false),
+            (dec!(6), dec!(0), false),
+            (dec!(2), dec!(0), true)
+        ]
+    );
+    assert_eq!(
+        run(WithdrawalDisputePolicy::CreditHeld),
+        [
+            (dec!(6), dec!(4), false),
+            (dec!(6), dec!(0), false),
+            (dec!(10), dec!(0), true)
+        ]
+    );
+    assert_eq!(
+        run(WithdrawalDisputePolicy::Reject),
+        [(dec!(6), dec!(0), false)]
+    );
+
+    // Shards follow the policy of the processor they were split from
+    let processor = TransactionProcessor::default().with_config(ProcessorConfig {
+        withdrawal_disputes: WithdrawalDisputePolicy::Reject,
+        ..Default::default()
+    });
+    let (processor, _) = ShardedProcessor::<()>::from_processor(processor, 3).finish();
+    assert_eq!(
+        processor.config().withdrawal_disputes,
+        WithdrawalDisputePolicy::Reject
+    );
+
+    // A dispute opened with one policy can't be settled with another after reloading the state
+    let credit_held = ProcessorConfig {
+        withdrawal_disputes: WithdrawalDisputePolicy::CreditHeld,
+        ..Default::default()
+    };
+    let mut generator = TransactionGenerator::default();
+    let mut processor = TransactionProcessor::default().with_config(credit_held.clone());
+    let client_id = ClientID::new(23);
+    let withdrawal = generator.transfer(client_id, dec!(-4));
+    assert!(processor
+        .process(&generator.transfer(client_id, dec!(10)))
+        .is_ok());
+    assert!(processor.process(&withdrawal).is_ok());
+    assert!(processor
+        .process(&generator.dispute(withdrawal.transaction_id()))
+        .is_ok());
+    let mut state = Vec::new();
+    processor.save_state(&mut state).unwrap();
+
+    let loaded = TransactionProcessor::load_state(state.as_slice()).unwrap();
+    assert!(matches!(
+        loaded.try_with_config(ProcessorConfig::default()),
+        Err(StateError::WithdrawalDisputePolicyMismatch {
+            saved: WithdrawalDisputePolicy::CreditHeld,
+            configured: WithdrawalDisputePolicy::HoldAvailable,
+        })
+    ));
+    let mut loaded = TransactionProcessor::load_state(state.as_slice())
+        .unwrap()
+        .try_with_config(credit_held)
+        .unwrap();
+    assert!(loaded
+        .process(&generator.resolve(withdrawal.transaction_id()))
+        .is_ok());
+    assert_eq!(loaded.accounts[&client_id].available, dec!(6));
+    assert_eq!(loaded.verify(), vec![]);
+}
+
+#[test]
+fn test_dispute_window() {
+    let input_csv = r#"type, client, tx, amount, reason, timestamp
+        deposit   , 1, 1, 10,  ,  1000
+        deposit   , 1, 2,  5,  ,  2000
+        deposit   , 1, 4,  3
+        dispute   , 1, 2,   ,  , 11000
+        deposit   , 1, 3,  1,  , 12000
+        dispute   , 1, 1,   ,  , 12500
+        deposit   , 1, 1,  7,  , 12500
+        resolve   , 1, 2,   ,  , 13000
+        dispute   , 1, 2
+        dispute   , 1, 3
+        dispute   , 1, 4,   ,  , 99000
+    "#;
+    let mut processor = TransactionProcessor::default().with_config(ProcessorConfig {
+        dispute_window: Some(std::time::Duration::from_secs(10)),
+        ..Default::default()
+    });
+    let results = CsvReader::from_reader(input_csv.as_bytes())
+        .map(|transaction| processor.process(&transaction).map_err(|err| err.code()))
+        .collect::<Vec<_>>();
+
+    assert_eq!(
+        results,
+        [
+            Ok(()),
+            Ok(()),
+            Ok(()),
+            Ok(()),
+            Ok(()),
+            Err("E213"),
+            Err("E209"),
+            Ok(()),
+            Err("E213"),
+            Ok(()),
+            Ok(())
+        ]
+    );
+    // Both the expired transfers were forgotten, the one in dispute only after the resolve
+    assert_eq!(processor.expired_transfers(), 2);
+    assert_eq!(processor.accounts[&ClientID::new(1)].held, dec!(4));
+
+    // Expired IDs are saved as ranges, while the states listing every ID still load
+    let mut state = Vec::new();
+    processor.save_state(&mut state).unwrap();
+    let state = String::from_utf8(state).unwrap();
+    assert!(state.contains(r#""expired":[[1,2]]"#));
+    for state in [state.clone(), state.replace("[[1,2]]", "[1,2]")].iter() {
+        let mut loaded = TransactionProcessor::load_state(state.as_bytes()).unwrap();
+        assert_eq!(loaded.expired_transfers(), 2);
+        let dispute = get_transactions("type, client, tx\ndispute, 1, 1").remove(0);
+        assert_eq!(
+            loaded.process(&dispute).map_err(|err| err.code()),
+            Err("E213")
+        );
+    }
+}
+
+#[test]
+fn test_timestamps() {
+    let input_csv = r#"type, client, tx, amount, reason, timestamp
+        deposit   , 1, 1, 10,  , 2024-03-01T12:00:00Z
+        deposit   , 1, 2,  5,  , 1709294400001
+        withdrawal, 1, 3,  1,  , 2024-03-01T13:00:00.5+01:00
+        dispute   , 1, 1,   ,  , 2024-03-01T12:00:00Z
+        deposit   , 1, 4,  1,  , yesterday
+    "#;
+    let transactions = CsvReader::from_reader(input_csv.as_bytes())
+        .records()
+        .map(|record| record.transaction)
+        .collect::<Vec<_>>();
+    assert!(transactions[4].is_err());
+    let transactions = transactions
+        .into_iter()
+        .filter_map(Result::ok)
+        .collect::<Vec<_>>();
+    assert_eq!(
+        transactions
+            .iter()
+            .map(|transaction| transaction.timestamp().unwrap().millis())
+            .collect::<Vec<_>>(),
+        [1709294400000, 1709294400001, 1709294400500, 1709294400000]
+    );
+
+    let input_jsonl = r#"{"type": "deposit", "client": 1, "tx": 1, "amount": "1", "timestamp": 1709294400000}
+{"type": "dispute", "client": 1, "tx": 1, "timestamp": "2024-03-01T12:00:00Z"}
+"#;
+    assert!(JsonLinesReader::from_reader(input_jsonl.as_bytes())
+        .all(|transaction| transaction.timestamp() == Some(Timestamp::from_millis(1709294400000))));
+
+    let results = |out_of_order| {
+        let mut processor = TransactionProcessor::default().with_config(ProcessorConfig {
+            out_of_order,
+            ..Default::default()
+        });
+        transactions
+            .iter()
+            .map(|transaction| processor.process(transaction).map_err(|err| err.code()))
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(results(OutOfOrderPolicy::Accept), [Ok(()); 4]);
+    assert_eq!(results(OutOfOrderPolicy::Warn), [Ok(()); 4]);
+    assert_eq!(
+        results(OutOfOrderPolicy::Reject),
+        [Ok(()), Ok(()), Ok(()), Err("E214")]
+    );
+}
+
+#[test]
+fn test_partial_dispute() {
+    let mut generator = TransactionGenerator::default();
+    let mut processor = TransactionProcessor::default();
+
+    let client_id = ClientID::new(23);
+    let deposit = generator.transfer(client_id, dec!(100));
+    assert!(processor.process(&deposit).is_ok());
+    let partial_dispute = |amount| {
+        Transaction::Amendment(Amendment {
+            amendment_type: AmendmentType::Dispute,
+            client_id,
+            transaction_id: deposit.transaction_id(),
+            amount: Some(amount),
+            timestamp: None,
+        })
+    };
+
+    // Disputed amount can't be larger than the transfer
+    assert!(matches!(
+        processor.process(&partial_dispute(dec!(100.01))),
+        Err(ProcessingError::DisputedAmountExceedsTransfer)
+    ));
+    assert!(processor.process(&partial_dispute(dec!(30))).is_ok());
+    assert_eq!(
+        processor.accounts[&client_id],
+        Account {
+            available: dec!(70),
+            held: dec!(30),
+            locked: false
+        }
+    );
+
+    // Only the disputed part is released on resolve
+    assert!(processor
+        .process(&generator.resolve(deposit.transaction_id()))
+        .is_ok());
+    assert_eq!(processor.accounts[&client_id].available, dec!(100));
+    assert_eq!(processor.accounts[&client_id].held, dec!(0));
+
+    // Only the disputed part is charged back and the rest can still be disputed
+    assert!(processor.process(&partial_dispute(dec!(40))).is_ok());
+    assert!(processor
+        .process(&generator.chargeback(deposit.transaction_id()))
+        .is_ok());
+    assert_eq!(
+        processor.accounts[&client_id],
+        Account {
+            available: dec!(60),
+            held: dec!(0),
+            locked: true
+        }
+    );
+    assert!(matches!(
+        processor.process(&partial_dispute(dec!(60.01))),
+        Err(ProcessingError::DisputedAmountExceedsTransfer)
+    ));
+    assert!(processor
+        .process(&generator.dispute(deposit.transaction_id()))
+        .is_ok());
+    assert_eq!(processor.accounts[&client_id].held, dec!(60));
+    assert!(processor
+        .process(&generator.chargeback(deposit.transaction_id()))
+        .is_ok());
+    assert!(matches!(
+        processor.process(&generator.dispute(deposit.transaction_id())),
+        Err(ProcessingError::DisputingAlreadyChargedBackTransfer)
+    ));
+    assert_eq!(processor.accounts[&client_id].available, dec!(0));
+}
+
 #[test]
 fn test_resolve() {
     let mut generator = TransactionGenerator::default();
@@ -291,6 +608,7 @@ fn transaction_with_the_same_id_isnt_allowed() {
         transaction_id: good_deposit.transaction_id(),
         transfer_type: TransferType::Deposit,
         amount: dec!(10),
+        timestamp: None,
     });
 
     assert!(processor.process(&good_deposit).is_ok());
@@ -318,6 +636,255 @@ fn transaction_with_the_same_id_isnt_allowed() {
         .is_err());
 }
 
+#[test]
+fn test_client_transfer() {
+    let mut processor = TransactionProcessor::default().with_config(ProcessorConfig {
+        record_history: true,
+        ..Default::default()
+    });
+    let input = "type,client,tx,amount,destination
+deposit,1,1,10,
+transfer,1,2,4,2
+transfer,1,3,7,2
+transfer,1,4,1,
+transfer,1,5,1,1
+dispute,2,2,,";
+    let results = CsvReader::from_reader(input.as_bytes())
+        .records()
+        .map(|record| {
+            let transaction = record.transaction.map_err(|err| err.name())?;
+            processor.process(&transaction).map_err(|err| err.name())
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(
+        results,
+        vec![
+            Ok(()),
+            Ok(()),
+            Err("NotEnoughMoneyForWithdrawal"),
+            Err("MissingDestination"),
+            Err("TransferToTheSameClient"),
+            Err("DisputingClientTransferIsNotAllowed"),
+        ]
+    );
+
+    let source = ClientID::new(1);
+    let destination = ClientID::new(2);
+    assert_eq!(processor.accounts[&source].available, dec!(6));
+    assert_eq!(processor.accounts[&destination].available, dec!(4));
+    let events = |client_id| {
+        processor
+            .history(client_id)
+            .iter()
+            .map(|entry| (entry.event_type, entry.amount))
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(
+        events(source),
+        vec![
+            (HistoryEventType::Deposit, Some(dec!(10))),
+            (HistoryEventType::Sent, Some(dec!(4)))
+        ]
+    );
+    assert_eq!(
+        events(destination),
+        vec![(HistoryEventType::Received, Some(dec!(4)))]
+    );
+
+    // The transaction ID is taken
+    let reused = Transaction::Transfer(Transfer {
+        transfer_type: TransferType::Deposit,
+        client_id: destination,
+        transaction_id: TransactionID::new(2),
+        amount: dec!(1),
+        timestamp: None,
+    });
+    assert_eq!(
+        processor.process(&reused).unwrap_err().name(),
+        "TransactionIdAlreadyExists"
+    );
+
+    // Locks of either side reject the transfer without changing any of the accounts
+    let mut generator = TransactionGenerator {
+        transaction_count: 5,
+        ..Default::default()
+    };
+    let locked = ClientID::new(3);
+    let deposit = generator.transfer(locked, dec!(5));
+    for transaction in [
+        deposit.clone(),
+        generator.transfer(locked, dec!(5)),
+        generator.dispute(deposit.transaction_id()),
+        generator.chargeback(deposit.transaction_id()),
+    ] {
+        assert!(processor.process(&transaction).is_ok());
+    }
+    let accounts = processor.accounts.clone();
+    assert_eq!(
+        processor
+            .process(&generator.client_transfer(source, locked, dec!(1)))
+            .unwrap_err()
+            .name(),
+        "TransferToLockedAccount"
+    );
+    assert_eq!(
+        processor
+            .process(&generator.client_transfer(locked, source, dec!(1)))
+            .unwrap_err()
+            .name(),
+        "TransferOnLockedAccount"
+    );
+    assert_eq!(processor.accounts, accounts);
+    assert_eq!(processor.verify(), vec![]);
+}
+
+#[test]
+fn test_fees() {
+    let mut processor = TransactionProcessor::default().with_config(ProcessorConfig {
+        record_history: true,
+        fees: FeeSchedule {
+            deposit: None,
+            withdrawal: Some("1%".parse().unwrap()),
+            chargeback: Some("5".parse().unwrap()),
+        },
+        ..Default::default()
+    });
+    let input = "type,client,tx,amount
+deposit,1,1,100
+withdrawal,1,2,10
+withdrawal,1,3,89.5
+deposit,1,4,20
+dispute,1,4,
+chargeback,1,4,
+fee,1,5,2.5
+fee,2,6,1
+fee,1,7,
+fee,1,8,100
+fee,1,5,2.5
+fee,1,1,1
+dispute,1,5,";
+    let results = CsvReader::from_reader(input.as_bytes())
+        .records()
+        .map(|record| {
+            let transaction = record.transaction.map_err(|err| err.name())?;
+            processor.process(&transaction).map_err(|err| err.name())
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(
+        results,
+        vec![
+            Ok(()),
+            Ok(()),
+            // The withdrawal fee has to be available as well
+            Err("NotEnoughMoneyForWithdrawal"),
+            Ok(()),
+            Ok(()),
+            Ok(()),
+            // Fees are charged on a locked account
+            Ok(()),
+            Err("FeeForUnknownClient"),
+            Err("MissingAmount"),
+            Err("NotEnoughMoneyForFee"),
+            // A replayed fee isn't charged twice, and fees can't reuse the IDs of the transfers
+            Err("TransactionIdAlreadyExists"),
+            Err("TransactionIdAlreadyExists"),
+            Err("DisputingFeeIsNotAllowed"),
+        ]
+    );
+
+    let client_id = ClientID::new(1);
+    assert_eq!(processor.accounts[&client_id].available, dec!(82.4));
+    assert_eq!(processor.fees_charged(client_id), dec!(7.6));
+    assert_eq!(
+        processor.ledger().balance(LedgerAccount::House),
+        LedgerBalance {
+            debit: dec!(0),
+            credit: dec!(7.6),
+        }
+    );
+    let fees = processor
+        .history(client_id)
+        .iter()
+        .filter(|entry| entry.event_type == HistoryEventType::Fee)
+        .map(|entry| (entry.transaction_id, entry.amount))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        fees,
+        vec![
+            (TransactionID::new(2), Some(dec!(0.1))),
+            (TransactionID::new(4), Some(dec!(5))),
+            (TransactionID::new(5), Some(dec!(2.5))),
+        ]
+    );
+    // The withdrawal and its fee have their own balances in the statement
+    let balances = processor.history(client_id)[1..3]
+        .iter()
+        .map(|entry| (entry.event_type, entry.account.available))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        balances,
+        vec![
+            (HistoryEventType::Withdrawal, dec!(90)),
+            (HistoryEventType::Fee, dec!(89.9)),
+        ]
+    );
+    assert_eq!(processor.verify(), vec![]);
+
+    assert_eq!(
+        FeeRate::Percentage(dec!(1.5)).fee(dec!(0.0999)),
+        dec!(0.0015)
+    );
+    assert!("-1".parse::<FeeRate>().is_err());
+
+    // The chargeback fee only takes the available funds that are left
+    let mut processor = TransactionProcessor::default().with_config(ProcessorConfig {
+        fees: FeeSchedule {
+            chargeback: Some("5".parse().unwrap()),
+            ..Default::default()
+        },
+        ..Default::default()
+    });
+    let client_id = ClientID::new(1);
+    let mut generator = TransactionGenerator::default();
+    let deposit = generator.transfer(client_id, dec!(10));
+    assert!(processor.process(&deposit).is_ok());
+    assert!(processor
+        .process(&generator.transfer(client_id, dec!(3)))
+        .is_ok());
+    assert!(processor
+        .process(&generator.dispute(deposit.transaction_id()))
+        .is_ok());
+    assert!(processor
+        .process(&generator.chargeback(deposit.transaction_id()))
+        .is_ok());
+    assert_eq!(processor.accounts[&client_id].available, dec!(0));
+    assert_eq!(processor.fees_charged(client_id), dec!(3));
+    assert_eq!(processor.verify(), vec![]);
+
+    // A deposit has to cover its own fee
+    let mut processor = TransactionProcessor::default().with_config(ProcessorConfig {
+        fees: FeeSchedule {
+            deposit: Some("0.5".parse().unwrap()),
+            ..Default::default()
+        },
+        ..Default::default()
+    });
+    let client_id = ClientID::new(1);
+    let mut generator = TransactionGenerator::default();
+    assert_eq!(
+        processor
+            .process(&generator.transfer(client_id, dec!(0.1)))
+            .map_err(|err| err.name()),
+        Err("NotEnoughMoneyForFee")
+    );
+    for amount in [dec!(1), dec!(0.1)] {
+        processor
+            .process(&generator.transfer(client_id, amount))
+            .unwrap();
+    }
+    assert_eq!(processor.accounts[&client_id].available, dec!(0.1));
+}
+
 #[test]
 fn test_csv_parsing_and_processing() {
     let input_csv = r#"type, client, tx, amount
@@ -354,6 +921,72 @@ fn test_csv_parsing_and_processing() {
     );
 }
 
+#[test]
+fn test_unlock_account() {
+    let input_csv = r#"type, client, tx, amount, reason
+        deposit   , 1, 1, 10
+        unlock    , 1, 2,   , Not locked yet
+        dispute   , 1, 1
+        chargeback, 1, 1
+        unlock    , 1, 1,   , Reused ID
+        deposit   , 1, 3,  5
+        unlock    , 1, 4
+        reinstate , 1, 5,   ,"Chargeback reversed, ticket 42"
+        deposit   , 1, 6,  5
+        deposit   , 1, 5,  1
+    "#;
+    let mut processor = TransactionProcessor::default();
+    let results = CsvReader::from_reader(input_csv.as_bytes())
+        .records()
+        .map(|record| match record.transaction {
+            Ok(transaction) => processor.process(&transaction).map_err(|err| err.code()),
+            Err(err) => Err(err.code()),
+        })
+        .collect::<Vec<_>>();
+
+    assert_eq!(
+        results,
+        [
+            Ok(()),
+            Err("E210"),
+            Ok(()),
+            Ok(()),
+            Err("E209"),
+            Err("E201"),
+            Err("E105"),
+            Ok(()),
+            Ok(()),
+            Err("E209")
+        ]
+    );
+    assert_eq!(
+        processor.accounts[&ClientID::new(1)],
+        Account {
+            available: dec!(5),
+            held: Decimal::zero(),
+            locked: false
+        }
+    );
+    assert_eq!(
+        processor.admin_actions(),
+        [AdminAction {
+            action_type: AdminActionType::Unlock,
+            client_id: ClientID::new(1),
+            transaction_id: TransactionID::new(5),
+            reason: "Chargeback reversed, ticket 42".to_owned()
+        }]
+    );
+    assert!(matches!(
+        processor.process(&Transaction::AdminAction(AdminAction {
+            action_type: AdminActionType::Unlock,
+            client_id: ClientID::new(2),
+            transaction_id: TransactionID::new(7),
+            reason: "Unknown client".to_owned()
+        })),
+        Err(ProcessingError::UnlockingAccountThatIsNotLocked)
+    ));
+}
+
 fn get_transactions(input_csv: &str) -> Vec<Transaction> {
     CsvReader::from_reader(input_csv.as_bytes()).collect::<Vec<_>>()
 }
@@ -362,6 +995,13 @@ fn extract_type(record: &Transaction) -> TransactionType {
     match record {
         Transaction::Transfer(transaction) => TransactionType::Transfer(transaction.transfer_type),
         Transaction::Amendment(amendment) => TransactionType::Amendment(amendment.amendment_type),
+        Transaction::AdminAction(admin_action) => {
+            TransactionType::AdminAction(admin_action.action_type)
+        }
+        Transaction::ClientTransfer(client_transfer) => {
+            TransactionType::ClientTransfer(client_transfer.transfer_type)
+        }
+        Transaction::Fee(fee) => TransactionType::Fee(fee.fee_type),
     }
 }
 
@@ -384,7 +1024,8 @@ fn test_csv_parsing_happy_cases() {
             transfer_type: TransferType::Deposit,
             amount: dec!(10),
             client_id: ClientID::new(1),
-            transaction_id: TransactionID::new(1)
+            transaction_id: TransactionID::new(1),
+            timestamp: None,
         })
     );
     assert_eq!(
@@ -393,7 +1034,8 @@ fn test_csv_parsing_happy_cases() {
             transfer_type: TransferType::Withdrawal,
             amount: dec!(20),
             client_id: ClientID::new(1),
-            transaction_id: TransactionID::new(2)
+            transaction_id: TransactionID::new(2),
+            timestamp: None,
         })
     );
     assert_eq!(
@@ -401,7 +1043,9 @@ fn test_csv_parsing_happy_cases() {
         Transaction::Amendment(Amendment {
             amendment_type: AmendmentType::Dispute,
             client_id: ClientID::new(2),
-            transaction_id: TransactionID::new(4)
+            transaction_id: TransactionID::new(4),
+            amount: None,
+            timestamp: None,
         })
     );
     assert_eq!(
@@ -409,7 +1053,9 @@ fn test_csv_parsing_happy_cases() {
         Transaction::Amendment(Amendment {
             amendment_type: AmendmentType::Resolve,
             client_id: ClientID::new(3),
-            transaction_id: TransactionID::new(5)
+            transaction_id: TransactionID::new(5),
+            amount: None,
+            timestamp: None,
         })
     );
     assert_eq!(
@@ -417,7 +1063,9 @@ fn test_csv_parsing_happy_cases() {
         Transaction::Amendment(Amendment {
             amendment_type: AmendmentType::Chargeback,
             client_id: ClientID::new(4),
-            transaction_id: TransactionID::new(10)
+            transaction_id: TransactionID::new(10),
+            amount: None,
+            timestamp: None,
         })
     );
 }
@@ -461,10 +1109,31 @@ fn test_csv_parsing_tricky_cases() {
         Transaction::Amendment(Amendment {
             amendment_type: AmendmentType::Dispute,
             client_id: ClientID::new(3),
-            transaction_id: TransactionID::new(5)
+            transaction_id: TransactionID::new(5),
+            amount: Some(dec!(8)),
+            timestamp: None,
+        })
+    );
+
+    let resolve_with_amount_csv = r#"type, client, tx, amount
+        resolve, 3, 5, 8"#;
+    let resolve_with_amount_transactions = get_transactions(resolve_with_amount_csv);
+    assert_eq!(resolve_with_amount_transactions.len(), 1);
+    assert_eq!(
+        resolve_with_amount_transactions[0],
+        Transaction::Amendment(Amendment {
+            amendment_type: AmendmentType::Resolve,
+            client_id: ClientID::new(3),
+            transaction_id: TransactionID::new(5),
+            amount: None,
+            timestamp: None,
         })
     );
 
+    let dispute_with_negative_amount_csv = r#"type, client, tx, amount
+        dispute, 3, 5, -8"#;
+    assert!(get_transactions(dispute_with_negative_amount_csv).is_empty());
+
     let withdrawal_without_amount_csv = r#"type, client, tx, amount
         withdrawal, 1, 2"#;
     assert!(get_transactions(withdrawal_without_amount_csv).is_empty());
@@ -494,3 +1163,1334 @@ fn test_csv_parsing_tricky_cases() {
         TransactionType::Transfer(TransferType::Withdrawal)
     );
 }
+
+#[test]
+fn test_rejects_report() {
+    let input_csv = r#"type, client, tx, amount
+        deposit, 1, 1, 12
+        banana
+        withdrawal, 1, 2, 20
+        deposit, 1, 3, -3
+        deposit, 1, 4, "1,5"
+    "#;
+    let mut csv_reader = CsvReader::from_reader(input_csv.as_bytes());
+    let mut processor = TransactionProcessor::default();
+    let mut reject_writer = RejectWriter::from_writer(Vec::new());
+    for record in csv_reader.records() {
+        match &record.transaction {
+            Ok(transaction) => {
+                if let Err(err) = processor.process(transaction) {
+                    reject_writer
+                        .write_processing_error(&record.position, &record.row, &err)
+                        .unwrap();
+                }
+            }
+            Err(err) => reject_writer
+                .write_input_error(&record.position, &record.row, err)
+                .unwrap(),
+        }
+    }
+
+    // Rows are reported as they were in the input, quoting included
+    let rejects = String::from_utf8(reject_writer.into_inner().unwrap()).unwrap();
+    assert_eq!(
+        rejects,
+        "line,byte,record,code,error,row\n\
+         3,51,2,E103,CsvError,banana\n\
+         4,66,3,E202,NotEnoughMoneyForWithdrawal,\"withdrawal, 1, 2, 20\"\n\
+         5,95,4,E102,NegativeAmount,\"deposit, 1, 3, -3\"\n\
+         6,121,5,E103,CsvError,\"deposit, 1, 4, \"\"1,5\"\"\"\n"
+    );
+
+    // Rows that aren't even valid UTF-8 are reported too
+    let input_csv = b"type,client,tx,amount\ndeposit,1,1,\xff\ndeposit,1,2,1\n";
+    let rows = CsvReader::from_reader(&input_csv[..])
+        .records()
+        .map(|record| (record.row, record.transaction.is_ok()))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        rows,
+        vec![
+            ("deposit,1,1,\u{fffd}".to_owned(), false),
+            ("deposit,1,2,1".to_owned(), true),
+        ]
+    );
+
+    // A resumed run adds to the rejects of the interrupted one, with a single header
+    let path = temporary_path("appended-rejects.csv");
+    let position = csv::Position::new();
+    for row in ["banana", "apple"] {
+        let mut reject_writer = RejectWriter::appending_to_path(&path).unwrap();
+        reject_writer
+            .write_input_error(&position, row, &InputFormatError::MissingAmount)
+            .unwrap();
+        reject_writer.flush().unwrap();
+    }
+    assert_eq!(
+        std::fs::read_to_string(&path).unwrap(),
+        "line,byte,record,code,error,row\n\
+         1,0,0,E101,MissingAmount,banana\n\
+         1,0,0,E101,MissingAmount,apple\n"
+    );
+    std::fs::remove_file(&path).ok();
+}
+
+#[test]
+fn test_csv_positions() {
+    let input_csv = "type,client,tx,amount\ndeposit,1,1,5\nwithdrawal,1,2\ndispute,1,1\n";
+    let mut csv_reader = CsvReader::from_reader(input_csv.as_bytes());
+    let positions = csv_reader
+        .positioned()
+        .map(|(position, result)| {
+            (
+                position.line(),
+                position.byte(),
+                position.record(),
+                result.is_ok(),
+            )
+        })
+        .collect::<Vec<_>>();
+
+    assert_eq!(
+        positions,
+        vec![(2, 22, 1, true), (3, 36, 2, false), (4, 51, 3, true)]
+    );
+}
+
+#[test]
+fn test_strict_processing() {
+    let mut processor = TransactionProcessor::default();
+    let input_csv = "type,client,tx,amount\ndeposit,1,1,5\nwithdrawal,1,2,7\ndeposit,1,3,1\n";
+    let err = processor
+        .process_strictly(&mut CsvReader::from_reader(input_csv.as_bytes()))
+        .unwrap_err();
+    assert_eq!(err.position.line(), 3);
+    assert_eq!(err.row, "withdrawal,1,2,7");
+    assert!(matches!(
+        err.error,
+        RecordError::ProcessingError(ProcessingError::NotEnoughMoneyForWithdrawal)
+    ));
+    assert_eq!(processor.accounts[&ClientID::new(1)].available, dec!(5));
+    assert_eq!(processor.applied_transactions(), 1);
+
+    let mut processor = TransactionProcessor::default();
+    let input_csv = "type,client,tx,amount\ndeposit,1,1,5\ndeposit,1,2\n";
+    let err = processor
+        .process_strictly(&mut CsvReader::from_reader(input_csv.as_bytes()))
+        .unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "line 3 (byte 36, record 2) failed with E101 MissingAmount"
+    );
+
+    let mut processor = TransactionProcessor::default();
+    let input_csv = "type,client,tx,amount\ndeposit,1,1,5\nwithdrawal,1,2,2\n";
+    assert!(processor
+        .process_strictly(&mut CsvReader::from_reader(input_csv.as_bytes()))
+        .is_ok());
+    assert_eq!(processor.applied_transactions(), 2);
+}
+
+#[test]
+fn test_state_snapshot_and_resume() {
+    let mut generator = TransactionGenerator::default();
+    let mut processor = TransactionProcessor::default();
+
+    let client_id = ClientID::new(23);
+    let deposit = generator.transfer(client_id, dec!(10));
+    let withdrawal = generator.transfer(client_id, dec!(-2.5));
+    let other_deposit = generator.transfer(client_id, dec!(1));
+    assert!(processor.process(&deposit).is_ok());
+    assert!(processor.process(&withdrawal).is_ok());
+    assert!(processor.process(&other_deposit).is_ok());
+    assert!(processor
+        .process(&generator.dispute(withdrawal.transaction_id()))
+        .is_ok());
+    assert!(processor
+        .process(&generator.dispute(other_deposit.transaction_id()))
+        .is_ok());
+    assert!(processor
+        .process(&generator.chargeback(other_deposit.transaction_id()))
+        .is_ok());
+
+    let mut snapshot = Vec::new();
+    processor.save_state(&mut snapshot).unwrap();
+    let mut resumed = TransactionProcessor::load_state(snapshot.as_slice()).unwrap();
+    assert_eq!(resumed.accounts, processor.accounts);
+
+    // Transfers and dispute state survive the round trip
+    assert!(resumed.process(&deposit).is_err());
+    assert!(resumed
+        .process(&generator.dispute(withdrawal.transaction_id()))
+        .is_err());
+    assert!(resumed
+        .process(&generator.dispute(other_deposit.transaction_id()))
+        .is_err());
+    assert!(resumed
+        .process(&generator.resolve(withdrawal.transaction_id()))
+        .is_ok());
+    assert_eq!(
+        *resumed.accounts.get(&client_id).unwrap(),
+        Account {
+            available: dec!(7.5),
+            held: Decimal::zero(),
+            locked: true
+        }
+    );
+
+    let unknown_version =
+        r#"{"version":0,"accounts":{},"transfers":{},"in_dispute":[],"charged_back":[]}"#;
+    assert!(TransactionProcessor::load_state(unknown_version.as_bytes()).is_err());
+
+    // Version 1 only had entire transfers in dispute
+    let version_1 = r#"{"version":1,
+        "accounts":{"5":{"available":"0","held":"4","locked":false}},
+        "transfers":{"1":{"transfer_type":"deposit","client_id":5,"transaction_id":1,"amount":"4"}},
+        "in_dispute":[1],"charged_back":[]}"#;
+    let mut resumed = TransactionProcessor::load_state(version_1.as_bytes()).unwrap();
+    let resolve = Transaction::Amendment(Amendment {
+        amendment_type: AmendmentType::Resolve,
+        client_id: ClientID::new(5),
+        transaction_id: TransactionID::new(1),
+        amount: None,
+        timestamp: None,
+    });
+    assert!(resumed.process(&resolve).is_ok());
+    assert_eq!(resumed.accounts[&ClientID::new(5)].available, dec!(4));
+}
+
+fn temporary_path(name: &str) -> std::path::PathBuf {
+    let path = std::env::temp_dir().join(format!(
+        "tiny-transaction-processor-{}-{}",
+        std::process::id(),
+        name
+    ));
+    std::fs::remove_file(&path).ok();
+    path
+}
+
+#[test]
+fn test_journal_resumes_interrupted_run() {
+    let input_path = temporary_path("journal-input.csv");
+    let journal_path = temporary_path("journal.jsonl");
+    let input = "input.csv";
+    std::fs::write(
+        &input_path,
+        r#"type, client, tx, amount
+        deposit,    1, 1, 10
+        withdrawal, 1, 2, 20
+        deposit,    2, 3, 5
+        dispute,    1, 1
+        withdrawal, 2, 4, 1
+        resolve,    1, 1
+    "#,
+    )
+    .unwrap();
+
+    let mut expected = TransactionProcessor::default();
+    for transaction in CsvReader::from_path(&input_path).unwrap() {
+        expected.process(&transaction).ok();
+    }
+
+    // The first run dies after the dispute, with its last journal write torn
+    {
+        let mut processor = TransactionProcessor::default();
+        let (mut journal, resume) = Journal::open(&journal_path, input, &mut processor).unwrap();
+        journal.set_batch_size(1);
+        assert!(resume.is_none());
+
+        let mut reader = CsvReader::from_path(&input_path).unwrap();
+        for _ in 0..4 {
+            let record = reader.next_record().unwrap();
+            let transaction = record.transaction.unwrap();
+            match processor.process(&transaction) {
+                Ok(()) => journal
+                    .record_applied(
+                        processor.applied_transactions(),
+                        &transaction,
+                        reader.current_position(),
+                    )
+                    .unwrap(),
+                Err(_) => journal.record_skipped(reader.current_position()).unwrap(),
+            }
+        }
+    }
+    let mut journal_file = std::fs::OpenOptions::new()
+        .append(true)
+        .open(&journal_path)
+        .unwrap();
+    std::io::Write::write_all(&mut journal_file, b"{\"entry\":\"applied\",\"seq").unwrap();
+
+    // Journal can't be used for a different input
+    assert!(Journal::open(
+        &journal_path,
+        "other.csv",
+        &mut TransactionProcessor::default()
+    )
+    .is_err());
+
+    // The second run replays the journal and only processes the remaining rows
+    let mut processor = TransactionProcessor::default();
+    let (_journal, resume) = Journal::open(&journal_path, input, &mut processor).unwrap();
+    assert_eq!(processor.applied_transactions(), 3);
+
+    let mut reader = CsvReader::from_path(&input_path).unwrap();
+    reader.seek(resume.unwrap()).unwrap();
+    let remaining = reader.collect::<Vec<_>>();
+    assert_eq!(remaining.len(), 2);
+    for transaction in remaining {
+        processor.process(&transaction).unwrap();
+    }
+    assert_eq!(processor.accounts, expected.accounts);
+
+    std::fs::remove_file(&input_path).ok();
+    std::fs::remove_file(&journal_path).ok();
+}
+
+#[test]
+fn test_file_transfer_store() {
+    let store_path = temporary_path("transfers.store");
+    let mut generator = TransactionGenerator::default();
+    let client_id = ClientID::new(3);
+    let deposit = generator.transfer(client_id, dec!(10));
+    let other_deposit = generator.transfer(client_id, dec!(4));
+    let withdrawal = generator.transfer(client_id, dec!(-2));
+
+    let store = FileTransferStore::open(&store_path).unwrap();
+    let mut processor = TransactionProcessor::default().with_store(Box::new(store));
+    assert!(processor.process(&deposit).is_ok());
+    assert!(processor.process(&other_deposit).is_ok());
+    assert!(processor.process(&withdrawal).is_ok());
+    assert!(processor
+        .process(&generator.dispute(deposit.transaction_id()))
+        .is_ok());
+    assert!(processor
+        .process(&generator.dispute(other_deposit.transaction_id()))
+        .is_ok());
+    assert!(processor
+        .process(&generator.chargeback(other_deposit.transaction_id()))
+        .is_ok());
+
+    // Transfers aren't a part of the state, so it can't be loaded without the store
+    let mut snapshot = Vec::new();
+    processor.save_state(&mut snapshot).unwrap();
+    drop(processor);
+    assert!(matches!(
+        TransactionProcessor::load_state(snapshot.as_slice()),
+        Err(StateError::TransferStoreRequired)
+    ));
+
+    let store = FileTransferStore::open(&store_path).unwrap();
+    let mut resumed =
+        TransactionProcessor::load_state_with_store(snapshot.as_slice(), Box::new(store)).unwrap();
+    assert!(resumed.process(&withdrawal).is_err());
+    assert!(resumed
+        .process(&generator.dispute(other_deposit.transaction_id()))
+        .is_err());
+    assert!(resumed
+        .process(&generator.resolve(deposit.transaction_id()))
+        .is_ok());
+    assert_eq!(
+        *resumed.accounts.get(&client_id).unwrap(),
+        Account {
+            available: dec!(8),
+            held: Decimal::zero(),
+            locked: true
+        }
+    );
+
+    // A regular state is moved into the store it's loaded with
+    let mut in_memory = TransactionProcessor::default();
+    assert!(in_memory.process(&deposit).is_ok());
+    let mut snapshot = Vec::new();
+    in_memory.save_state(&mut snapshot).unwrap();
+    let other_store_path = temporary_path("other-transfers.store");
+    let store = FileTransferStore::open(&other_store_path).unwrap();
+    let mut resumed =
+        TransactionProcessor::load_state_with_store(snapshot.as_slice(), Box::new(store)).unwrap();
+    assert!(resumed
+        .process(&generator.dispute(deposit.transaction_id()))
+        .is_ok());
+    assert_eq!(resumed.accounts[&client_id].held, dec!(10));
+
+    for path in [store_path, other_store_path].iter() {
+        std::fs::remove_file(path).ok();
+        std::fs::remove_file(path.with_extension("store.undo")).ok();
+    }
+}
+
+#[test]
+fn test_file_transfer_store_failed_run() {
+    let store_path = temporary_path("failed-run.store");
+    let state_path = temporary_path("failed-run.state");
+    let mut generator = TransactionGenerator::default();
+    let client_id = ClientID::new(5);
+    let deposit = generator.transfer(client_id, dec!(10));
+    let other_deposit = generator.transfer(client_id, dec!(4));
+
+    let store = FileTransferStore::open(&store_path).unwrap();
+    let mut processor = TransactionProcessor::default().with_store(Box::new(store));
+    assert!(processor.process(&deposit).is_ok());
+    processor.save_state_to_path(&state_path).unwrap();
+    drop(processor);
+    let saved_state = std::fs::read(&state_path).unwrap();
+
+    // The run fails before saving the state, leaving its changes in the store
+    let run = |generator: &mut TransactionGenerator| {
+        let store = FileTransferStore::open(&store_path).unwrap();
+        let mut processor =
+            TransactionProcessor::load_state_from_path_with_store(&state_path, Box::new(store))
+                .unwrap();
+        assert!(processor.process(&other_deposit).is_ok());
+        assert!(processor
+            .process(&generator.dispute(deposit.transaction_id()))
+            .is_ok());
+        processor
+    };
+    drop(run(&mut generator));
+
+    // The rerun starts from the saved state, as if the failed run never happened
+    let mut processor = run(&mut generator);
+    assert_eq!(
+        processor.accounts[&client_id],
+        Account {
+            available: dec!(4),
+            held: dec!(10),
+            locked: false
+        }
+    );
+    processor.save_state_to_path(&state_path).unwrap();
+    drop(processor);
+
+    // An older state doesn't belong to the committed store anymore
+    let store = FileTransferStore::open(&store_path).unwrap();
+    assert!(matches!(
+        TransactionProcessor::load_state_with_store(saved_state.as_slice(), Box::new(store)),
+        Err(StateError::TransferStoreMismatch { state: 1, store: 2 })
+    ));
+    let store = FileTransferStore::open(&store_path).unwrap();
+    let processor =
+        TransactionProcessor::load_state_from_path_with_store(&state_path, Box::new(store))
+            .unwrap();
+    assert_eq!(processor.accounts[&client_id].held, dec!(10));
+
+    std::fs::remove_file(&store_path).ok();
+    std::fs::remove_file(store_path.with_extension("store.undo")).ok();
+    std::fs::remove_file(&state_path).ok();
+}
+
+#[test]
+fn test_compact_transfer_store() {
+    let transfer = |id: u32, amount: Decimal, timestamp: Option<u64>| Transfer {
+        transfer_type: TransferType::Deposit,
+        client_id: ClientID::new(7),
+        transaction_id: TransactionID::new(id),
+        amount,
+        timestamp: timestamp.map(Timestamp::from_millis),
+    };
+    let small = transfer(1, dec!(12.3456), None);
+    let large = transfer(5000, dec!(79228162514264337593543950335), Some(42));
+    let last = transfer(u32::MAX, dec!(0.5), None);
+
+    let mut store = CompactTransferStore::default();
+    for transfer in [&small, &large, &last] {
+        store.insert_transfer(transfer.clone());
+        assert_eq!(
+            store.transfer(transfer.transaction_id).as_ref(),
+            Some(transfer)
+        );
+    }
+    assert!(store.transfer(TransactionID::new(2)).is_none());
+
+    // Only partial amounts are remembered separately, entire ones follow the transfer
+    store.set_disputed_amount(small.transaction_id, Some(dec!(2)));
+    store.set_disputed_amount(large.transaction_id, Some(large.amount));
+    store.set_charged_back_amount(last.transaction_id, dec!(0.5));
+    assert_eq!(store.disputed_amount(small.transaction_id), Some(dec!(2)));
+    assert_eq!(
+        store.disputed_amount(large.transaction_id),
+        Some(large.amount)
+    );
+    assert_eq!(
+        store.charged_back_amount(last.transaction_id),
+        Some(dec!(0.5))
+    );
+    assert_eq!(store.disputed_amounts().count(), 2);
+
+    store.set_disputed_amount(small.transaction_id, None);
+    assert!(store.disputed_amount(small.transaction_id).is_none());
+    store.remove_transfer(last.transaction_id);
+    assert!(store.transfer(last.transaction_id).is_none());
+    assert!(store.charged_back_amount(last.transaction_id).is_none());
+    let transfers = store.transfers().collect::<Vec<_>>();
+    assert_eq!(transfers.len(), 2);
+    assert!(transfers.contains(&small) && transfers.contains(&large));
+
+    // A page filling up moves its transfers out of the hash maps, with their disputes
+    store.set_disputed_amount(small.transaction_id, Some(dec!(2)));
+    for id in 2..1000 {
+        store.insert_transfer(transfer(id, dec!(1), None));
+    }
+    assert_eq!(store.transfer(small.transaction_id).as_ref(), Some(&small));
+    assert_eq!(store.disputed_amount(small.transaction_id), Some(dec!(2)));
+    assert_eq!(store.transfers().count(), 1000);
+    assert_eq!(store.disputed_amounts().count(), 2);
+}
+
+#[test]
+fn test_server_replies_per_line() {
+    use std::io::{BufRead, Write};
+
+    let processor = std::sync::Arc::new(std::sync::Mutex::new(TransactionProcessor::default()));
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap();
+    let server_processor = std::sync::Arc::clone(&processor);
+    std::thread::spawn(move || server::serve_tcp(server_processor, listener));
+
+    let mut stream = std::net::TcpStream::connect(address).unwrap();
+    stream
+        .write_all(
+            b"deposit, 1, 1, 10\n\
+              withdrawal, 1, 2, 20\n\
+              \n\
+              deposit, 1, 3, -3\n\
+              dispute, 1, 1\n\
+              query, 1\n\
+              query, 2\n",
+        )
+        .unwrap();
+
+    let replies = std::io::BufReader::new(stream)
+        .lines()
+        .take(6)
+        .collect::<Result<Vec<_>, _>>()
+        .unwrap();
+    assert_eq!(
+        replies,
+        vec![
+            "ok",
+            "error,E202,NotEnoughMoneyForWithdrawal",
+            "error,E102,NegativeAmount",
+            "ok",
+            "ok,1,0,10,10,false",
+            "error,E301,UnknownClient",
+        ]
+    );
+    assert_eq!(
+        processor
+            .lock()
+            .unwrap()
+            .accounts
+            .get(&ClientID::new(1))
+            .unwrap()
+            .held,
+        dec!(10)
+    );
+}
+
+#[test]
+fn test_server_saves_state_periodically() {
+    let state_path = temporary_path("served.state");
+    let processor = std::sync::Arc::new(std::sync::Mutex::new(TransactionProcessor::default()));
+    let saver = std::sync::Arc::new(server::StateSaver::new(
+        std::sync::Arc::clone(&processor),
+        state_path.clone(),
+    ));
+    std::sync::Arc::clone(&saver).save_periodically(std::time::Duration::from_millis(10));
+    assert_eq!(server::respond(&processor, "deposit, 1, 1, 10"), "ok");
+    assert_eq!(server::respond(&processor, "dispute, 1, 1"), "ok");
+
+    // The state is saved once both transactions are applied
+    let mut saved = None;
+    for _ in 0..500 {
+        std::thread::sleep(std::time::Duration::from_millis(10));
+        if let Ok(processor) = TransactionProcessor::load_state_from_path(&state_path) {
+            if processor.applied_transactions() == 2 {
+                saved = Some(processor);
+                break;
+            }
+        }
+    }
+    let mut saved = saved.expect("State wasn't saved");
+    assert_eq!(saved.accounts[&ClientID::new(1)].held, dec!(10));
+    let resolve = CsvReader::without_headers("resolve, 1, 1".as_bytes())
+        .next_record()
+        .unwrap()
+        .transaction
+        .unwrap();
+    assert!(saved.process(&resolve).is_ok());
+
+    // Nothing is written without new transactions, and the shutdown save includes the latest ones
+    std::fs::remove_file(&state_path).unwrap();
+    saver.save().unwrap();
+    assert!(!state_path.exists());
+    assert_eq!(server::respond(&processor, "resolve, 1, 1"), "ok");
+    saver.save().unwrap();
+    let saved = TransactionProcessor::load_state_from_path(&state_path).unwrap();
+    assert_eq!(saved.accounts[&ClientID::new(1)].held, dec!(0));
+
+    std::fs::remove_file(&state_path).ok();
+}
+
+fn http_request(
+    address: std::net::SocketAddr,
+    method: &str,
+    path: &str,
+    body: &str,
+) -> (u16, String) {
+    use std::io::{Read, Write};
+
+    let mut stream = std::net::TcpStream::connect(address).unwrap();
+    write!(
+        stream,
+        "{} {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\nContent-Length: {}\r\n\r\n{}",
+        method,
+        path,
+        body.len(),
+        body
+    )
+    .unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+
+    let status = response[9..12].parse().unwrap();
+    let body = response.split("\r\n\r\n").nth(1).unwrap().to_owned();
+    (status, body)
+}
+
+#[test]
+fn test_http_api() {
+    let processor = std::sync::Arc::new(std::sync::Mutex::new(TransactionProcessor::default()));
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap();
+    std::thread::spawn(move || http::serve_http(processor, listener));
+
+    assert_eq!(
+        http_request(
+            address,
+            "POST",
+            "/transactions",
+            r#"{"type": "deposit", "client": 1, "tx": 1, "amount": 10.1}"#
+        ),
+        (
+            200,
+            r#"{"client":1,"available":"10.1","held":"0","total":"10.1","locked":false}"#
+                .to_owned()
+        )
+    );
+    assert_eq!(
+        http_request(
+            address,
+            "POST",
+            "/transactions",
+            r#"{"type": "withdrawal", "client": 1, "tx": 2, "amount": "20"}"#
+        ),
+        (
+            422,
+            r#"{"code":"E202","error":"NotEnoughMoneyForWithdrawal"}"#.to_owned()
+        )
+    );
+    assert_eq!(
+        http_request(
+            address,
+            "POST",
+            "/transactions",
+            r#"{"type": "withdrawal", "client": 1, "tx": 2}"#
+        )
+        .0,
+        400
+    );
+    assert_eq!(
+        http_request(
+            address,
+            "POST",
+            "/transactions",
+            r#"{"type": "dispute", "client": 1, "tx": 7}"#
+        )
+        .0,
+        404
+    );
+    assert_eq!(
+        http_request(
+            address,
+            "POST",
+            "/transactions",
+            r#"{"type": "dispute", "client": 1, "tx": 1}"#
+        )
+        .0,
+        200
+    );
+    assert_eq!(
+        http_request(
+            address,
+            "POST",
+            "/transactions",
+            r#"{"type": "deposit", "client": 2, "tx": 1, "amount": "1"}"#
+        )
+        .0,
+        409
+    );
+    assert_eq!(
+        http_request(address, "GET", "/accounts/1", ""),
+        (
+            200,
+            r#"{"client":1,"available":"0","held":"10.1","total":"10.1","locked":false}"#
+                .to_owned()
+        )
+    );
+    assert_eq!(http_request(address, "GET", "/accounts/2", "").0, 404);
+    assert_eq!(http_request(address, "DELETE", "/accounts/1", "").0, 405);
+}
+
+#[test]
+fn test_sharded_processing_matches_sequential() {
+    let mut generator = TransactionGenerator::default();
+    let mut transactions = Vec::new();
+    for round in 0..50u16 {
+        for client in 0..20u16 {
+            let client_id = ClientID::new(client);
+            let deposit = generator.transfer(client_id, Decimal::from(round + client + 1));
+            let withdrawal = generator.transfer(client_id, -Decimal::from(round * 2 + 1));
+            let dispute = generator.dispute(deposit.transaction_id());
+            transactions.push(deposit.clone());
+            transactions.push(withdrawal);
+            transactions.push(dispute);
+            if (round + client) % 3 == 0 {
+                transactions.push(generator.chargeback(deposit.transaction_id()));
+            } else {
+                transactions.push(generator.resolve(deposit.transaction_id()));
+            }
+            // Transfers to clients of other shards and of the same one
+            let destination = ClientID::new((client + 1 + round % 2 * 3) % 20);
+            transactions.push(generator.client_transfer(
+                client_id,
+                destination,
+                Decimal::from(round % 7 + 1),
+            ));
+            // Transaction IDs reused by clients of other shards, of an applied transfer and of a
+            // rejected one, which leaves its ID free
+            if client % 5 == 0 {
+                let reused = |transaction_id| {
+                    Transaction::Transfer(Transfer {
+                        transfer_type: TransferType::Deposit,
+                        client_id: ClientID::new((client + 1) % 20),
+                        transaction_id,
+                        amount: dec!(3),
+                        timestamp: None,
+                    })
+                };
+                let overdraft = generator.transfer(client_id, dec!(-1000000));
+                transactions.push(reused(deposit.transaction_id()));
+                transactions.push(overdraft.clone());
+                transactions.push(reused(overdraft.transaction_id()));
+            }
+        }
+    }
+
+    let mut sequential = TransactionProcessor::default();
+    let mut sequential_rejections = Vec::new();
+    for (index, transaction) in transactions.iter().enumerate() {
+        if sequential.process(transaction).is_err() {
+            sequential_rejections.push(index);
+        }
+    }
+
+    // Start from a state that has to be split between the shards too
+    let mut initial = TransactionProcessor::default();
+    let (head, tail) = transactions.split_at(100);
+    for transaction in head {
+        initial.process(transaction).ok();
+    }
+    let mut sharded = ShardedProcessor::from_processor(initial, 4);
+    for (index, transaction) in tail.iter().enumerate() {
+        sharded.submit(index + head.len(), transaction.clone());
+    }
+    let (merged, rejected) = sharded.finish();
+    let mut sharded_rejections = rejected
+        .into_iter()
+        .map(|rejected| rejected.tag)
+        .collect::<Vec<_>>();
+    sharded_rejections.sort_unstable();
+
+    assert_eq!(merged.accounts, sequential.accounts);
+    assert_eq!(merged.ledger(), sequential.ledger());
+    assert_eq!(
+        merged.applied_transactions(),
+        sequential.applied_transactions()
+    );
+    assert_eq!(
+        sharded_rejections,
+        sequential_rejections
+            .into_iter()
+            .filter(|index| *index >= head.len())
+            .collect::<Vec<_>>()
+    );
+}
+
+#[test]
+fn test_json_lines_parsing() {
+    let input_jsonl = r#"{"type": "deposit", "client": 1, "tx": 1, "amount": "10.5"}
+{"transaction_type": "withdrawal", "client_id": 1, "transaction_id": 2, "amount": 2}
+
+{"type": "dispute", "client": 1, "tx": 1}
+{"type": "withdrawal", "client": 1, "tx": 3}
+{"type": "deposit", "client": 1, "tx": 4, "amount": -3}
+{"type": "banana", "client": 1, "tx": 5}
+not json at all
+"#;
+
+    let mut reader = JsonLinesReader::from_reader(input_jsonl.as_bytes());
+    let records = reader.records().collect::<Vec<_>>();
+    assert_eq!(
+        records
+            .iter()
+            .map(|record| record.position.line())
+            .collect::<Vec<_>>(),
+        vec![1, 2, 4, 5, 6, 7, 8]
+    );
+    assert_eq!(
+        records
+            .iter()
+            .map(|record| record.transaction.as_ref().map_err(InputFormatError::code))
+            .map(|result| result.map(extract_type))
+            .collect::<Vec<_>>(),
+        vec![
+            Ok(TransactionType::Transfer(TransferType::Deposit)),
+            Ok(TransactionType::Transfer(TransferType::Withdrawal)),
+            Ok(TransactionType::Amendment(AmendmentType::Dispute)),
+            Err("E101"),
+            Err("E102"),
+            Err("E104"),
+            Err("E104"),
+        ]
+    );
+
+    let csv_transactions = get_transactions(
+        r#"type, client, tx, amount
+        deposit, 1, 1, 10.5
+        withdrawal, 1, 2, 2
+        dispute, 1, 1"#,
+    );
+    let json_transactions =
+        JsonLinesReader::from_reader(input_jsonl.as_bytes()).collect::<Vec<_>>();
+    assert_eq!(json_transactions, csv_transactions);
+
+    // A line that isn't valid UTF-8 is rejected, but still counted in the following positions
+    let mut invalid_utf8 = br#"{"type": "deposit", "client": 1, "tx": 1, "amount": "1"}"#.to_vec();
+    invalid_utf8.extend_from_slice(b"\n{\"type\": \"dep\xffosit\"}\n");
+    invalid_utf8.extend_from_slice(br#"{"type": "deposit", "client": 1, "tx": 2, "amount": "2"}"#);
+    let mut reader = JsonLinesReader::from_reader(std::io::Cursor::new(invalid_utf8));
+    let records = reader.records().collect::<Vec<_>>();
+    assert_eq!(
+        records
+            .iter()
+            .map(|record| (
+                record.position.line(),
+                record.position.byte(),
+                record
+                    .transaction
+                    .as_ref()
+                    .err()
+                    .map(InputFormatError::code)
+            ))
+            .collect::<Vec<_>>(),
+        vec![(1, 0, None), (2, 57, Some("E104")), (3, 78, None)]
+    );
+    assert_eq!(records[1].row, "{\"type\": \"dep\u{fffd}osit\"}");
+    reader.seek(records[2].position.clone()).unwrap();
+    assert_eq!(
+        reader
+            .next()
+            .map(|transaction| transaction.transaction_id()),
+        Some(TransactionID::new(2))
+    );
+}
+
+#[test]
+fn test_account_history() {
+    let mut processor = TransactionProcessor::default().with_config(ProcessorConfig {
+        record_history: true,
+        ..Default::default()
+    });
+    let mut generator = TransactionGenerator::default();
+    let client_id = ClientID::new(1);
+    let deposit = generator.transfer(client_id, dec!(10));
+    let withdrawal = generator.transfer(client_id, dec!(-2.5));
+    assert!(processor.process(&deposit).is_ok());
+    assert!(processor.process(&withdrawal).is_ok());
+    assert!(processor
+        .process(&generator.transfer(client_id, dec!(-100)))
+        .is_err());
+    assert!(processor
+        .process(&generator.dispute(deposit.transaction_id()))
+        .is_ok());
+    assert!(processor
+        .process(&generator.chargeback(deposit.transaction_id()))
+        .is_ok());
+    assert!(processor
+        .process(&generator.transfer(ClientID::new(2), dec!(1)))
+        .is_ok());
+
+    // Rejected transactions and other clients don't show up
+    let history = processor.history(client_id);
+    assert_eq!(
+        history
+            .iter()
+            .map(|entry| (entry.event_type, entry.amount, entry.account.available))
+            .collect::<Vec<_>>(),
+        vec![
+            (HistoryEventType::Deposit, Some(dec!(10)), dec!(10)),
+            (HistoryEventType::Withdrawal, Some(dec!(2.5)), dec!(7.5)),
+            (HistoryEventType::Hold, Some(dec!(10)), dec!(-2.5)),
+            (HistoryEventType::Chargeback, Some(dec!(10)), dec!(-2.5)),
+            (HistoryEventType::Lock, None, dec!(-2.5)),
+        ]
+    );
+    assert_eq!(history[2].account.held, dec!(10));
+    assert!(history[4].account.locked);
+
+    let mut output = Vec::new();
+    write_statement(OutputFormat::Csv, &mut output, &history[..2]).unwrap();
+    assert_eq!(
+        String::from_utf8(output).unwrap(),
+        "tx,type,amount,available,held,total,locked\n\
+         1,deposit,10,10,0,10,false\n\
+         2,withdrawal,2.5,7.5,0,7.5,false\n"
+    );
+
+    // History is only kept when asked for
+    let mut processor = TransactionProcessor::default();
+    assert!(processor.process(&deposit).is_ok());
+    assert!(processor.history(client_id).is_empty());
+}
+
+#[test]
+fn test_observer() {
+    #[derive(Default)]
+    struct EventLog {
+        events: std::sync::Mutex<Vec<(String, Decimal, Decimal)>>,
+    }
+
+    impl Observer for EventLog {
+        fn on_event(&self, event: &AccountEvent) {
+            let description = match event.event_type {
+                AccountEventType::Rejected(error) => format!("{:?}", error),
+                event_type => format!("{:?}", event_type),
+            };
+            self.events.lock().unwrap().push((
+                description,
+                event.before.available,
+                event.after.available,
+            ));
+        }
+    }
+
+    let log = std::sync::Arc::new(EventLog::default());
+    let mut processor = TransactionProcessor::default().with_observer(log.clone());
+    let mut generator = TransactionGenerator::default();
+    let client_id = ClientID::new(4);
+    let deposit = generator.transfer(client_id, dec!(3));
+    assert!(processor.process(&deposit).is_ok());
+    assert!(processor
+        .process(&generator.transfer(client_id, dec!(-5)))
+        .is_err());
+    assert!(processor
+        .process(&generator.dispute(deposit.transaction_id()))
+        .is_ok());
+    assert!(processor
+        .process(&generator.chargeback(deposit.transaction_id()))
+        .is_ok());
+
+    assert_eq!(
+        *log.events.lock().unwrap(),
+        vec![
+            ("Deposited(3)".to_owned(), dec!(0), dec!(3)),
+            ("NotEnoughMoneyForWithdrawal".to_owned(), dec!(3), dec!(3)),
+            ("FundsHeld(3)".to_owned(), dec!(3), dec!(0)),
+            ("ChargedBack(3)".to_owned(), dec!(0), dec!(0)),
+            ("AccountLocked".to_owned(), dec!(0), dec!(0)),
+        ]
+    );
+}
+
+#[test]
+fn test_verify() {
+    let mut processor = TransactionProcessor::default().with_config(ProcessorConfig {
+        withdrawal_disputes: WithdrawalDisputePolicy::CreditHeld,
+        ..Default::default()
+    });
+    let mut generator = TransactionGenerator::default();
+    let client_id = ClientID::new(6);
+    let deposit = generator.transfer(client_id, dec!(10));
+    let withdrawal = generator.transfer(client_id, dec!(-4));
+    for transaction in [
+        deposit.clone(),
+        withdrawal.clone(),
+        generator.dispute(withdrawal.transaction_id()),
+        generator.chargeback(withdrawal.transaction_id()),
+        generator.dispute(deposit.transaction_id()),
+    ] {
+        assert!(processor.process(&transaction).is_ok());
+    }
+    assert_eq!(processor.verify(), vec![]);
+
+    // Balances that don't follow from the transfers are reported
+    processor.accounts.get_mut(&client_id).unwrap().held += dec!(1);
+    assert_eq!(
+        processor.verify(),
+        vec![
+            Discrepancy::LedgerMismatch {
+                client_id,
+                available: dec!(0),
+                held: dec!(10),
+            },
+            Discrepancy::HeldMismatch {
+                client_id,
+                held: dec!(11),
+                disputed: dec!(10),
+            },
+            Discrepancy::TotalMismatch {
+                client_id,
+                total: dec!(11),
+                expected: dec!(10),
+            },
+        ]
+    );
+
+    let overdisputed = r#"{"version":2,
+        "accounts":{"5":{"available":"0","held":"4","locked":false}},
+        "transfers":{"1":{"transfer_type":"deposit","client_id":5,"transaction_id":1,"amount":"4"}},
+        "in_dispute":{"1":"4","2":"1"},"charged_back":{"1":"1"}}"#;
+    let processor = TransactionProcessor::load_state(overdisputed.as_bytes()).unwrap();
+    let discrepancies = processor.verify();
+    assert!(discrepancies.contains(&Discrepancy::UnknownTransfer(TransactionID::new(2))));
+    assert!(discrepancies.contains(&Discrepancy::TransferOverdisputed {
+        transaction_id: TransactionID::new(1),
+        amount: dec!(4),
+        disputed: dec!(4),
+        charged_back: dec!(1),
+    }));
+}
+
+#[test]
+fn test_ledger_trial_balance() {
+    let mut processor = TransactionProcessor::default().with_config(ProcessorConfig {
+        withdrawal_disputes: WithdrawalDisputePolicy::CreditHeld,
+        record_postings: true,
+        ..Default::default()
+    });
+    let mut generator = TransactionGenerator::default();
+    let client_id = ClientID::new(1);
+    let withdrawal = generator.transfer(client_id, dec!(-2.5));
+    for transaction in [
+        generator.transfer(client_id, dec!(10)),
+        withdrawal.clone(),
+        generator.dispute(withdrawal.transaction_id()),
+        generator.chargeback(withdrawal.transaction_id()),
+    ] {
+        assert!(processor.process(&transaction).is_ok());
+    }
+
+    let ledger = processor.ledger();
+    assert_eq!(ledger.client_balances(client_id), (dec!(10), dec!(0)));
+    assert_eq!(
+        ledger.balance(LedgerAccount::ChargebackLoss),
+        LedgerBalance {
+            debit: dec!(2.5),
+            credit: dec!(0),
+        }
+    );
+    assert_eq!(
+        ledger.balance(LedgerAccount::DisputesClearing),
+        LedgerBalance {
+            debit: dec!(2.5),
+            credit: dec!(2.5),
+        }
+    );
+
+    let trial_balance = ledger.trial_balance();
+    assert_eq!(
+        trial_balance.first(),
+        Some(&TrialBalanceRow {
+            account: Some(LedgerAccount::Cash),
+            debit: dec!(7.5),
+            credit: dec!(0),
+        })
+    );
+    assert_eq!(
+        trial_balance.last(),
+        Some(&TrialBalanceRow {
+            account: None,
+            debit: dec!(10),
+            credit: dec!(10),
+        })
+    );
+
+    // Every posting is kept with the transaction that made it
+    let postings = ledger
+        .postings(client_id)
+        .iter()
+        .map(|entry| (entry.transaction_id, entry.posting.amount))
+        .collect::<Vec<_>>();
+    let (deposit_id, withdrawal_id) = (TransactionID::new(2), withdrawal.transaction_id());
+    assert_eq!(
+        postings,
+        vec![
+            (deposit_id, dec!(10)),
+            (withdrawal_id, dec!(2.5)),
+            (withdrawal_id, dec!(2.5)),
+            (withdrawal_id, dec!(2.5)),
+            (withdrawal_id, dec!(2.5)),
+        ]
+    );
+    assert_eq!(
+        ledger.postings(client_id)[4].posting,
+        Posting::new(
+            LedgerAccount::ChargebackLoss,
+            LedgerAccount::DisputesClearing,
+            dec!(2.5)
+        )
+    );
+
+    // The ledger is saved with the state
+    let mut state = Vec::new();
+    processor.save_state(&mut state).unwrap();
+    let loaded = TransactionProcessor::load_state(state.as_slice()).unwrap();
+    assert_eq!(loaded.ledger(), processor.ledger());
+
+    // Without recording, only the balances are kept and saved
+    let mut processor = TransactionProcessor::default();
+    assert!(processor
+        .process(&generator.transfer(client_id, dec!(10)))
+        .is_ok());
+    assert!(processor.ledger().postings(client_id).is_empty());
+    assert_eq!(
+        processor.ledger().client_balances(client_id),
+        (dec!(10), dec!(0))
+    );
+    let mut state = Vec::new();
+    processor.save_state(&mut state).unwrap();
+    assert!(!String::from_utf8(state).unwrap().contains("postings"));
+}
+
+#[test]
+fn test_account_output_formats() {
+    let accounts = [
+        (
+            ClientID::new(2),
+            Account {
+                available: dec!(12.8000),
+                held: dec!(15),
+                locked: false,
+            },
+        ),
+        (
+            ClientID::new(42),
+            Account {
+                available: dec!(8.0),
+                held: Decimal::zero(),
+                locked: true,
+            },
+        ),
+    ];
+    let output = |output_format| {
+        let mut output = Vec::new();
+        write_accounts(
+            output_format,
+            &mut output,
+            accounts
+                .iter()
+                .map(|(client_id, account)| AccountWithClientID { client_id, account }),
+        )
+        .unwrap();
+        String::from_utf8(output).unwrap()
+    };
+
+    assert_eq!(
+        output(OutputFormat::Csv),
+        "client,available,held,total,locked\n\
+         2,12.8,15,27.8,false\n\
+         42,8,0,8,true\n"
+    );
+    assert_eq!(
+        output(OutputFormat::Json),
+        "[\n  \
+         {\"client\":2,\"available\":\"12.8\",\"held\":\"15\",\"total\":\"27.8\",\"locked\":false},\n  \
+         {\"client\":42,\"available\":\"8\",\"held\":\"0\",\"total\":\"8\",\"locked\":true}\n\
+         ]\n"
+    );
+    assert_eq!(
+        output(OutputFormat::JsonLines),
+        "{\"client\":2,\"available\":\"12.8\",\"held\":\"15\",\"total\":\"27.8\",\"locked\":false}\n\
+         {\"client\":42,\"available\":\"8\",\"held\":\"0\",\"total\":\"8\",\"locked\":true}\n"
+    );
+    assert_eq!(
+        output(OutputFormat::Table),
+        " client | available | held | total | locked\n\
+         --------+-----------+------+-------+-------\n      \
+              2 |      12.8 |   15 |  27.8 |  false\n     \
+             42 |         8 |    0 |     8 |   true\n"
+    );
+
+    let mut empty_json = Vec::new();
+    write_accounts(OutputFormat::Json, &mut empty_json, std::iter::empty()).unwrap();
+    assert_eq!(empty_json, b"[]\n");
+}
+
+#[test]
+fn test_sorted_accounts() {
+    let mut transaction_processor = TransactionProcessor::default();
+    let mut generator = TransactionGenerator::default();
+    for (client, amount) in [(7, dec!(5)), (3, dec!(20)), (12, dec!(5)), (1, dec!(9))] {
+        transaction_processor
+            .process(&generator.transfer(ClientID::new(client), amount))
+            .unwrap();
+    }
+    transaction_processor
+        .process(&generator.dispute(TransactionID::new(2)))
+        .unwrap();
+    transaction_processor
+        .process(&generator.chargeback(TransactionID::new(2)))
+        .unwrap();
+    transaction_processor
+        .process(&generator.dispute(TransactionID::new(3)))
+        .unwrap();
+
+    let order = |account_order| {
+        transaction_processor
+            .sorted_accounts(account_order)
+            .map(|account| *account.client_id)
+            .collect::<Vec<_>>()
+    };
+    let clients = |ids: [u16; 4]| ids.iter().copied().map(ClientID::new).collect::<Vec<_>>();
+    assert_eq!(order(AccountOrder::ClientId), clients([1, 3, 7, 12]));
+    assert_eq!(order(AccountOrder::Total), clients([3, 7, 12, 1]));
+    assert_eq!(order(AccountOrder::Available), clients([3, 12, 7, 1]));
+    assert_eq!(order(AccountOrder::Held), clients([1, 3, 7, 12]));
+    assert_eq!(order(AccountOrder::Locked), clients([1, 7, 12, 3]));
+}
+
+fn arbitrary_transaction(
+    (kind, client, tx, amount, timestamp): (u8, u16, u32, i64, Option<u64>),
+) -> Transaction {
+    let client_id = ClientID::new(client);
+    let transaction_id = TransactionID::new(tx);
+    let amount = Decimal::new(amount, 1);
+    let timestamp = timestamp.map(Timestamp::from_millis);
+    let transfer = |transfer_type| {
+        Transaction::Transfer(Transfer {
+            transfer_type,
+            client_id,
+            transaction_id,
+            amount,
+            timestamp,
+        })
+    };
+    let amendment = |amendment_type, amount| {
+        Transaction::Amendment(Amendment {
+            amendment_type,
+            client_id,
+            transaction_id,
+            amount,
+            timestamp,
+        })
+    };
+    match kind {
+        0 => transfer(TransferType::Deposit),
+        1 => transfer(TransferType::Withdrawal),
+        2 => amendment(AmendmentType::Dispute, None),
+        3 => amendment(AmendmentType::Dispute, Some(amount)),
+        4 => amendment(AmendmentType::Resolve, None),
+        5 => amendment(AmendmentType::Chargeback, None),
+        6 => Transaction::ClientTransfer(ClientTransfer {
+            transfer_type: ClientTransferType::Transfer,
+            client_id,
+            destination_client_id: ClientID::new(client % 3 + 1),
+            transaction_id,
+            amount,
+            timestamp,
+        }),
+        7 => Transaction::Fee(Fee {
+            fee_type: FeeType::Fee,
+            client_id,
+            transaction_id,
+            amount,
+            timestamp,
+        }),
+        _ => Transaction::AdminAction(AdminAction {
+            action_type: AdminActionType::Unlock,
+            client_id,
+            transaction_id,
+            reason: "test".to_owned(),
+        }),
+    }
+}
+
+// Percentages of the odd amounts make the rounding of the fees likely
+fn arbitrary_fees() -> FeeSchedule {
+    FeeSchedule {
+        deposit: None,
+        withdrawal: Some(FeeRate::Flat(dec!(0.5))),
+        chargeback: Some(FeeRate::Percentage(dec!(2.5))),
+    }
+}
+
+fn state_snapshot(processor: &mut TransactionProcessor) -> serde_json::Value {
+    let mut state = Vec::new();
+    processor.save_state(&mut state).unwrap();
+    serde_json::from_slice(&state).unwrap()
+}
+
+proptest::proptest! {
+    // Small ranges of clients, IDs and timestamps make most of the rejection reasons likely
+    #[test]
+    fn rejected_transactions_leave_no_trace(
+        transactions in proptest::collection::vec(
+            (0u8..9, 1u16..4, 1u32..9, 0i64..200, proptest::option::of(0u64..30)),
+            1..60,
+        ),
+        withdrawal_disputes in proptest::sample::select(vec![
+            WithdrawalDisputePolicy::HoldAvailable,
+            WithdrawalDisputePolicy::CreditHeld,
+            WithdrawalDisputePolicy::Reject,
+        ]),
+    ) {
+        let mut processor = TransactionProcessor::default().with_config(ProcessorConfig {
+            withdrawal_disputes,
+            dispute_window: Some(std::time::Duration::from_millis(10)),
+            out_of_order: OutOfOrderPolicy::Reject,
+            record_history: true,
+            record_postings: true,
+            fees: arbitrary_fees(),
+        });
+        for transaction in transactions.into_iter().map(arbitrary_transaction) {
+            let before = state_snapshot(&mut processor);
+            if processor.process(&transaction).is_err() {
+                proptest::prop_assert_eq!(state_snapshot(&mut processor), before);
+            }
+        }
+    }
+
+    #[test]
+    fn processed_ledger_verifies(
+        transactions in proptest::collection::vec(
+            (0u8..9, 1u16..4, 1u32..9, 0i64..200, proptest::option::of(0u64..30)),
+            1..60,
+        ),
+        withdrawal_disputes in proptest::sample::select(vec![
+            WithdrawalDisputePolicy::HoldAvailable,
+            WithdrawalDisputePolicy::CreditHeld,
+            WithdrawalDisputePolicy::Reject,
+        ]),
+    ) {
+        let mut processor = TransactionProcessor::default().with_config(ProcessorConfig {
+            withdrawal_disputes,
+            fees: arbitrary_fees(),
+            ..Default::default()
+        });
+        for transaction in transactions.into_iter().map(arbitrary_transaction) {
+            processor.process(&transaction).ok();
+            proptest::prop_assert_eq!(processor.verify(), vec![]);
+        }
+    }
+}